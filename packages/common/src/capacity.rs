@@ -0,0 +1,30 @@
+//! Wire types of the worker node's `GET /capacity`, which the master node uses to decide how
+//! many proofs to claim and where to dispatch them.
+
+use serde::{Deserialize, Serialize};
+
+/// Installation state of a worker's PlonK and Groth16 circuit artifacts.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// The artifacts are being downloaded.
+    #[default]
+    Downloading,
+    /// The artifacts are installed and wrapped proofs can be generated.
+    Ready,
+    /// The last download failed. Another attempt is made after a delay.
+    Failed,
+}
+
+/// Proving capacity of a worker node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capacity {
+    /// Number of proofs the worker can generate concurrently.
+    pub slots: usize,
+    /// Number of slots not taken by a queued or running job.
+    pub available: usize,
+    /// Whether PlonK and Groth16 proofs can be generated yet. Workers that don't report it are
+    /// assumed not ready for wrapped proofs.
+    #[serde(default)]
+    pub circuits: CircuitState,
+}
@@ -0,0 +1,49 @@
+//! Wire types of the worker node's asynchronous job API.
+
+use serde::{Deserialize, Serialize};
+
+/// The lifecycle of a proof job on a worker node.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Fetching,
+    Executing,
+    Proving,
+    Verifying,
+    Uploading,
+    Fulfilling,
+    Failed,
+    Done,
+}
+
+impl JobStatus {
+    /// Whether the job has reached a terminal state.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Failed | JobStatus::Done)
+    }
+}
+
+/// Time spent in a single phase of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: JobStatus,
+    pub seconds: f64,
+}
+
+/// A proof job as reported by the worker's `GET /jobs/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub proof_id: String,
+    pub status: JobStatus,
+    /// The error message if the job failed.
+    pub error: Option<String>,
+    /// The proving time reported by the network once the proof is fulfilled.
+    pub proving_seconds: Option<u64>,
+    /// The cycle count of the program, once executed.
+    #[serde(default)]
+    pub cycles: Option<u64>,
+    /// Time spent in each phase the job went through, in order.
+    pub timings: Vec<PhaseTiming>,
+}
@@ -0,0 +1,7 @@
+//! Code shared by the master node and the worker nodes.
+
+/// The `ClusterService` protocol the master node serves and the worker nodes call.
+pub mod artifact;
+pub mod capacity;
+pub mod jobs;
+pub mod telemetry;
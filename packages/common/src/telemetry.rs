@@ -0,0 +1,111 @@
+//! Logging and trace export setup of both nodes.
+
+use clap::ValueEnum;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_sdk::{
+    runtime,
+    trace::{Config as TraceConfig, Sampler, Tracer, TracerProvider},
+    Resource,
+};
+use serde::Deserialize;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Output format of the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+/// Where spans are exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtelExporter {
+    None,
+    Otlp,
+    File,
+}
+
+/// The part of a node's configuration that sets up logging and trace export.
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    pub log_format: LogFormat,
+    pub otel_exporter: OtelExporter,
+    pub otel_exporter_otlp_endpoint: String,
+    pub otel_exporter_file: String,
+    pub otel_sampling_ratio: f64,
+    pub otel_service_name: String,
+}
+
+/// Install the global subscriber. Events are filtered by `RUST_LOG` (default `info`) and written
+/// as JSON, or as plain text with `log_format = "text"`. Records of the `log` crate are forwarded
+/// to the subscriber so they carry the span they were logged in. Spans are also exported when
+/// `otel_exporter` is set, see [`tracer`].
+pub fn init(config: &TelemetryConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let otel = tracer(config).map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    let registry = tracing_subscriber::registry().with(filter).with(otel);
+    match config.log_format {
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_span_list(true))
+            .init(),
+        LogFormat::Text => registry.with(fmt::layer()).init(),
+    }
+}
+
+/// Build the tracer spans are exported with, selected by `otel_exporter`:
+///
+/// - `none` exports nothing.
+/// - `otlp` sends spans over gRPC to `otel_exporter_otlp_endpoint`.
+/// - `file` appends spans as JSON to `otel_exporter_file`, as a local stand-in for a collector.
+///
+/// `otel_sampling_ratio` is the share of proof requests whose traces are kept, and
+/// `otel_service_name` names this node in the traces.
+fn tracer(config: &TelemetryConfig) -> Option<Tracer> {
+    let trace_config = TraceConfig::default()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.otel_sampling_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.otel_service_name.clone(),
+        )]));
+
+    let provider = match config.otel_exporter {
+        OtelExporter::None => return None,
+        OtelExporter::Otlp => opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&config.otel_exporter_otlp_endpoint),
+            )
+            .with_trace_config(trace_config)
+            .install_batch(runtime::Tokio)
+            .expect("Failed to create the OTLP exporter"),
+        OtelExporter::File => {
+            let path = &config.otel_exporter_file;
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| panic!("Failed to open OTEL_EXPORTER_FILE {}: {}", path, e));
+            let exporter = opentelemetry_stdout::SpanExporter::builder()
+                .with_writer(file)
+                .build();
+            TracerProvider::builder()
+                .with_config(trace_config)
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .build()
+        }
+    };
+    opentelemetry::global::set_tracer_provider(provider.clone());
+
+    Some(provider.tracer(config.otel_service_name.clone()))
+}
+
+/// Export the spans that are still buffered. Called once before the node exits.
+pub async fn shutdown() {
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
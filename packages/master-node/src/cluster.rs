@@ -0,0 +1,1174 @@
+//! Implementation of the `ClusterService` task protocol, served by the master node.
+//!
+//! Tasks, artifacts, claimed proofs and CPU cycle usage are kept in a local sled database so the
+//! cluster state survives restarts of the master node.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use log::info;
+use prover_common::artifact::{
+    router, Artifact, BumpTasksRequest, BumpTasksResponse, ClusterService,
+    CreateArtifactBatchRequest, CreateArtifactBatchResponse, CreateArtifactRequest,
+    CreateArtifactResponse, CreateClaimedProofRequest, CreateClaimedProofResponse,
+    CreateTaskBatchRequest, CreateTaskBatchResponse, CreateTaskRequest, CreateTaskResponse,
+    ExpireDroppedTasksRequest, ExpireDroppedTasksResponse, FetchTaskBatchStatusRequest,
+    FetchTaskBatchStatusResponse, FetchTaskStatusRequest, FetchTaskStatusResponse,
+    FetchTaskStatusesRequest, FetchTaskStatusesResponse, GetFailedProofRequestsRequest,
+    GetFailedProofRequestsResponse, GetFulfillableProofRequestsRequest,
+    GetFulfillableProofRequestsResponse, ModifyCpuCyclesRequest, ModifyCpuCyclesResponse,
+    PopTasksRequest, PopTasksResponse, ProofRequestStatus, Task, TaskData, TaskRequestData,
+    TaskStatus, TaskType, UpdateProofStatusRequest, UpdateProofStatusResponse,
+    UpdateTaskStatusRequest, UpdateTaskStatusResponse, WorkerType, SERVICE_FQN,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use twirp::{Context, TwirpErrorResponse};
+
+use crate::config::config;
+use crate::ledger::now;
+use crate::listener::ProofRequest;
+use crate::metrics::metrics_handler;
+
+/// Worker recorded in the ledger for proof requests handed to the cluster service.
+pub const CLUSTER_WORKER: &str = "cluster";
+
+/// Running tasks that have not been bumped for this long are considered dropped.
+const TASK_HEARTBEAT_TIMEOUT_SECS: u64 = 5 * 60;
+
+/// Weight of controller tasks. They mostly wait on their children, so they don't count against
+/// the proving budget of workers.
+const CONTROLLER_TASK_WEIGHT: u32 = 0;
+
+/// How many times a task that failed with a retryable error is put back in the queue.
+const MAX_TASK_ATTEMPTS: u32 = 3;
+
+/// How long the tasks of a settled proof request are kept, so their workers can still report on
+/// them, before they are deleted.
+const SETTLED_PROOF_RETENTION_SECS: u64 = 60 * 60;
+
+/// Value of index entries, only their keys matter.
+const INDEXED: &[u8] = &[];
+
+/// A task together with the scheduling state that is not part of the protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TaskRecord {
+    task: Task,
+    weight: u32,
+    attempts: u32,
+    /// Unix timestamp of the last time the task was popped or bumped, in seconds.
+    heartbeat_at: u64,
+}
+
+/// A proof request claimed by the cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClaimedProofRecord {
+    request: CreateClaimedProofRequest,
+    status: i32,
+    /// CPU cycles charged for this proof request.
+    cycles: i64,
+}
+
+fn internal(e: impl std::fmt::Display) -> TwirpErrorResponse {
+    twirp::internal(e.to_string())
+}
+
+fn month() -> String {
+    chrono::Utc::now().format("%Y-%m").to_string()
+}
+
+/// Whether a worker of the given type should be handed tasks of the given type.
+///
+/// CPU workers run the controllers and precompile events, GPU workers run the stages that commit
+/// to and prove shards. PlonK and Groth16 wrapping stays on CPU workers as its prover does not
+/// use the GPU.
+fn accepts(worker_type: WorkerType, task_type: TaskType) -> bool {
+    match worker_type {
+        WorkerType::All => true,
+        WorkerType::Cpu => matches!(
+            task_type,
+            TaskType::Sp1ControllerExecute
+                | TaskType::Sp1ControllerCommit
+                | TaskType::Sp1ControllerOpen
+                | TaskType::Sp1ControllerRecursionCore
+                | TaskType::Sp1ControllerRecursionDeferred
+                | TaskType::Sp1ControllerRecursionReduce
+                | TaskType::Sp1ControllerPlonk
+                | TaskType::Sp1PrecompileShard
+        ),
+        WorkerType::Gpu => matches!(
+            task_type,
+            TaskType::Sp1CommitShard
+                | TaskType::Sp1OpenMultiShard
+                | TaskType::Sp1RecursionCoreBatch
+                | TaskType::Sp1RecursionDeferredBatch
+                | TaskType::Sp1RecursionReduceBatch
+                | TaskType::Sp1ControllerShrink
+                | TaskType::Sp1ControllerWrap
+        ),
+        // No tasks are scheduled on Modal yet.
+        WorkerType::Modal | WorkerType::Unspecified => false,
+    }
+}
+
+/// Whether a task reached a status it doesn't leave anymore.
+fn is_finished(status: i32) -> bool {
+    status == TaskStatus::Succeeded as i32 || status == TaskStatus::FailedFatal as i32
+}
+
+/// Key of a task or batch in the index of the tasks of a proof request.
+fn proof_key(proof_id: &str, id: &str) -> String {
+    format!("{}/{}", proof_id, id)
+}
+
+/// The sled backed state of the cluster.
+///
+/// Claimed proofs only hold the proof requests the cluster is working on. Tasks are indexed by
+/// status and by proof request, so that the queue can be worked through without reading every
+/// task, and are deleted some time after their proof request is settled.
+pub struct ClusterStore {
+    db: sled::Db,
+    artifacts: sled::Tree,
+    tasks: sled::Tree,
+    batches: sled::Tree,
+    claimed_proofs: sled::Tree,
+    cpu_cycles: sled::Tree,
+    /// IDs of the pending tasks, in the order they are popped.
+    pending_tasks: sled::Tree,
+    /// IDs of the running tasks.
+    running_tasks: sled::Tree,
+    /// `<proof ID>/<task or batch ID>` of the tasks and batches of each proof request.
+    proof_tasks: sled::Tree,
+    /// `<settled at>/<proof ID>` of the settled proof requests whose tasks are still kept.
+    settled_proofs: sled::Tree,
+    /// Serializes read-modify-write cycles on the trees.
+    lock: Mutex<()>,
+}
+
+impl ClusterStore {
+    /// Open the store at the given path, creating it if needed.
+    pub fn open(path: &str) -> Result<Self> {
+        let db = sled::open(path)?;
+
+        let store = Self {
+            artifacts: db.open_tree("artifacts")?,
+            tasks: db.open_tree("tasks")?,
+            batches: db.open_tree("batches")?,
+            claimed_proofs: db.open_tree("claimed_proofs")?,
+            cpu_cycles: db.open_tree("cpu_cycles")?,
+            pending_tasks: db.open_tree("pending_tasks")?,
+            running_tasks: db.open_tree("running_tasks")?,
+            proof_tasks: db.open_tree("proof_tasks")?,
+            settled_proofs: db.open_tree("settled_proofs")?,
+            db,
+            lock: Mutex::new(()),
+        };
+        store.rebuild_indexes()?;
+
+        Ok(store)
+    }
+
+    /// Rebuild the task indexes, which stores written by older versions don't have, and settle
+    /// the claimed proofs they kept after they were settled.
+    fn rebuild_indexes(&self) -> Result<()> {
+        self.pending_tasks.clear()?;
+        self.running_tasks.clear()?;
+        for record in Self::values::<TaskRecord>(&self.tasks)? {
+            self.index_task(&record)?;
+        }
+        for record in Self::values::<ClaimedProofRecord>(&self.claimed_proofs)? {
+            if record.status != ProofRequestStatus::Claimed as i32 {
+                self.settle_proof(&record.request.proof_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get<T: DeserializeOwned>(tree: &sled::Tree, key: &str) -> Result<Option<T>> {
+        tree.get(key)?
+            .map(|value| serde_json::from_slice(&value).map_err(anyhow::Error::from))
+            .transpose()
+    }
+
+    fn put<T: Serialize>(tree: &sled::Tree, key: &str, value: &T) -> Result<()> {
+        tree.insert(key, serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    fn values<T: DeserializeOwned>(tree: &sled::Tree) -> Result<Vec<T>> {
+        tree.iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    /// Generate a unique ID. IDs sort in creation order so that tasks are popped first in, first out.
+    fn generate_id(&self, prefix: &str) -> Result<String> {
+        Ok(format!("{}_{:016x}", prefix, self.db.generate_id()?))
+    }
+
+    fn create_artifact(&self, label: &str, expiry: u32) -> Result<Artifact> {
+        let artifact = Artifact {
+            id: self.generate_id("artifact")?,
+            label: label.to_string(),
+            expiry: Some(expiry as i32),
+        };
+        Self::put(&self.artifacts, &artifact.id, &artifact)?;
+
+        Ok(artifact)
+    }
+
+    fn artifact(&self, id: &str) -> Result<Artifact> {
+        Ok(Self::get(&self.artifacts, id)?.unwrap_or_else(|| Artifact {
+            id: id.to_string(),
+            label: String::new(),
+            expiry: None,
+        }))
+    }
+
+    /// Write a task and keep the status index up to date.
+    fn put_task(&self, record: &TaskRecord) -> Result<()> {
+        Self::put(&self.tasks, &record.task.id, record)?;
+        self.index_task(record)
+    }
+
+    fn index_task(&self, record: &TaskRecord) -> Result<()> {
+        let id = record.task.id.as_str();
+        self.proof_tasks
+            .insert(proof_key(&record.task.proof_id, id), INDEXED)?;
+        match TaskStatus::try_from(record.task.status) {
+            Ok(TaskStatus::Pending) => {
+                self.pending_tasks.insert(id, INDEXED)?;
+                self.running_tasks.remove(id)?;
+            }
+            Ok(TaskStatus::Running) => {
+                self.pending_tasks.remove(id)?;
+                self.running_tasks.insert(id, INDEXED)?;
+            }
+            _ => {
+                self.pending_tasks.remove(id)?;
+                self.running_tasks.remove(id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The tasks created for a proof request.
+    fn tasks_of(&self, proof_id: &str) -> Result<Vec<TaskRecord>> {
+        let prefix = proof_key(proof_id, "");
+        let mut tasks = Vec::new();
+        for key in self.proof_tasks.scan_prefix(&prefix).keys() {
+            let key = key?;
+            let id = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+            if let Some(record) = Self::get(&self.tasks, &id)? {
+                tasks.push(record);
+            }
+        }
+
+        Ok(tasks)
+    }
+
+    fn create_task(
+        &self,
+        parent_id: Option<String>,
+        proof_id: &str,
+        data: TaskRequestData,
+    ) -> Result<String> {
+        let id = self.generate_id("task")?;
+        let inputs = data
+            .input_artifact_ids
+            .iter()
+            .map(|id| self.artifact(id))
+            .collect::<Result<Vec<_>>>()?;
+        let outputs = data
+            .output_artifact_ids
+            .iter()
+            .map(|id| self.artifact(id))
+            .collect::<Result<Vec<_>>>()?;
+        let record = TaskRecord {
+            task: Task {
+                id: id.clone(),
+                parent_id,
+                proof_id: proof_id.to_string(),
+                data: Some(TaskData {
+                    task_type: data.task_type,
+                    inputs,
+                    outputs,
+                    metadata: data.metadata,
+                }),
+                status: TaskStatus::Pending as i32,
+            },
+            weight: data.weight,
+            attempts: 0,
+            heartbeat_at: now(),
+        };
+        self.put_task(&record)?;
+
+        Ok(id)
+    }
+
+    fn create_task_batch(
+        &self,
+        parent_id: Option<String>,
+        proof_id: &str,
+        data: Vec<TaskRequestData>,
+    ) -> Result<String> {
+        let id = self.generate_id("batch")?;
+        let task_ids = data
+            .into_iter()
+            .map(|data| self.create_task(parent_id.clone(), proof_id, data))
+            .collect::<Result<Vec<_>>>()?;
+        Self::put(&self.batches, &id, &task_ids)?;
+        self.proof_tasks.insert(proof_key(proof_id, &id), INDEXED)?;
+
+        Ok(id)
+    }
+
+    fn batch(&self, id: &str) -> Result<Option<Vec<String>>> {
+        Self::get(&self.batches, id)
+    }
+
+    fn task(&self, id: &str) -> Result<TaskRecord> {
+        Self::get(&self.tasks, id)?.ok_or_else(|| anyhow::anyhow!("Task '{}' not found", id))
+    }
+
+    fn task_status(&self, id: &str) -> Result<i32> {
+        Ok(self.task(id)?.task.status)
+    }
+
+    /// Set and write the status of a task. Retryable failures are put back in the queue until the
+    /// task runs out of attempts. Tasks that finished already, eg. because they were cancelled,
+    /// keep their status.
+    fn set_task_status(&self, record: &mut TaskRecord, status: TaskStatus) -> Result<()> {
+        if is_finished(record.task.status) {
+            return Ok(());
+        }
+        record.task.status = status as i32;
+        if status == TaskStatus::FailedRetryable {
+            record.attempts += 1;
+            record.task.status = if record.attempts < MAX_TASK_ATTEMPTS {
+                TaskStatus::Pending as i32
+            } else {
+                TaskStatus::FailedFatal as i32
+            };
+        }
+        self.put_task(record)?;
+
+        // A retried controller creates its children again, so the ones of the failed attempt are
+        // of no use anymore.
+        if record.task.status == TaskStatus::Pending as i32
+            || record.task.status == TaskStatus::FailedFatal as i32
+        {
+            let children = self
+                .tasks_of(&record.task.proof_id)?
+                .into_iter()
+                .filter(|child| child.task.parent_id.as_deref() == Some(record.task.id.as_str()));
+            self.cancel_tasks(children)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fail the given tasks for good unless they finished already, so they are not run anymore.
+    fn cancel_tasks(&self, records: impl IntoIterator<Item = TaskRecord>) -> Result<()> {
+        for mut record in records {
+            if !is_finished(record.task.status) {
+                record.task.status = TaskStatus::FailedFatal as i32;
+                self.put_task(&record)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pop_tasks(&self, worker_type: WorkerType, max_weight: u32) -> Result<Vec<Task>> {
+        let _guard = self.lock.lock().unwrap();
+
+        let mut popped = Vec::new();
+        let mut weight = 0;
+        for id in self.pending_tasks.iter().keys() {
+            let id = String::from_utf8_lossy(&id?).into_owned();
+            let mut record = self.task(&id)?;
+            let task_type = record
+                .task
+                .data
+                .as_ref()
+                .and_then(|data| TaskType::try_from(data.task_type).ok())
+                .unwrap_or(TaskType::UnspecifiedTaskType);
+            if !accepts(worker_type, task_type) {
+                continue;
+            }
+            // Tasks of proof requests that were settled in the meantime are not run anymore.
+            if !self.claimed_proofs.contains_key(&record.task.proof_id)? {
+                self.cancel_tasks([record])?;
+                continue;
+            }
+            if weight + record.weight > max_weight {
+                continue;
+            }
+
+            weight += record.weight;
+            record.task.status = TaskStatus::Running as i32;
+            record.heartbeat_at = now();
+            self.put_task(&record)?;
+            popped.push(record.task);
+        }
+
+        Ok(popped)
+    }
+
+    fn bump_tasks(&self, ids: &[String]) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        for id in ids {
+            // Tasks of settled proofs are pruned while workers may still be running them.
+            let Some(mut record) = Self::get::<TaskRecord>(&self.tasks, id)? else {
+                continue;
+            };
+            record.heartbeat_at = now();
+            Self::put(&self.tasks, id, &record)?;
+        }
+
+        Ok(())
+    }
+
+    fn update_task_status(&self, id: &str, status: TaskStatus) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        let mut record = self.task(id)?;
+        self.set_task_status(&mut record, status)
+    }
+
+    fn expire_dropped_tasks(&self) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        let deadline = now().saturating_sub(TASK_HEARTBEAT_TIMEOUT_SECS);
+        for id in self.running_tasks.iter().keys() {
+            let id = String::from_utf8_lossy(&id?).into_owned();
+            let mut record = self.task(&id)?;
+            if record.heartbeat_at < deadline {
+                info!("Task '{}' was dropped by its worker.", record.task.id);
+                self.set_task_status(&mut record, TaskStatus::FailedRetryable)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Claimed proof requests, together with the tasks created for each.
+    fn claimed_proofs_with_tasks(&self) -> Result<Vec<(ClaimedProofRecord, Vec<Task>)>> {
+        Self::values::<ClaimedProofRecord>(&self.claimed_proofs)?
+            .into_iter()
+            .map(|proof| {
+                let tasks = self.tasks_of(&proof.request.proof_id)?;
+                Ok((proof, tasks.into_iter().map(|record| record.task).collect()))
+            })
+            .collect()
+    }
+
+    /// Claimed proof requests whose controller task succeeded.
+    fn fulfillable_proofs(&self) -> Result<Vec<String>> {
+        Ok(self
+            .claimed_proofs_with_tasks()?
+            .into_iter()
+            .filter(|(_, tasks)| {
+                tasks.iter().any(|task| {
+                    task.parent_id.is_none() && task.status == TaskStatus::Succeeded as i32
+                })
+            })
+            .map(|(proof, _)| proof.request.proof_id)
+            .collect())
+    }
+
+    /// Claimed proof requests whose controller task failed for good. Children that failed for
+    /// good only fail the current attempt of their controller, which may still be retried.
+    fn failed_proofs(&self) -> Result<Vec<String>> {
+        Ok(self
+            .claimed_proofs_with_tasks()?
+            .into_iter()
+            .filter(|(_, tasks)| {
+                tasks.iter().any(|task| {
+                    task.parent_id.is_none() && task.status == TaskStatus::FailedFatal as i32
+                })
+            })
+            .map(|(proof, _)| proof.request.proof_id)
+            .collect())
+    }
+
+    fn create_claimed_proof(&self, request: CreateClaimedProofRequest) -> Result<()> {
+        let proof_id = request.proof_id.clone();
+        Self::put(
+            &self.claimed_proofs,
+            &proof_id,
+            &ClaimedProofRecord {
+                request,
+                status: ProofRequestStatus::Claimed as i32,
+                cycles: 0,
+            },
+        )
+    }
+
+    fn claimed_proof(&self, proof_id: &str) -> Result<ClaimedProofRecord> {
+        Self::get(&self.claimed_proofs, proof_id)?
+            .ok_or_else(|| anyhow::anyhow!("Claimed proof '{}' not found", proof_id))
+    }
+
+    fn update_proof_status(&self, proof_id: &str, status: ProofRequestStatus) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        if status != ProofRequestStatus::Claimed {
+            return self.settle_proof(proof_id);
+        }
+        let mut record = self.claimed_proof(proof_id)?;
+        record.status = status as i32;
+        Self::put(&self.claimed_proofs, proof_id, &record)
+    }
+
+    /// Stop tracking a proof request the cluster is done with and cancel its unfinished tasks.
+    /// Its tasks are deleted once `SETTLED_PROOF_RETENTION_SECS` have passed. Settling a proof
+    /// request twice is a no-op.
+    fn settle_proof(&self, proof_id: &str) -> Result<()> {
+        if self.claimed_proofs.remove(proof_id)?.is_none() {
+            return Ok(());
+        }
+        self.cancel_tasks(self.tasks_of(proof_id)?)?;
+        let key = format!("{:016x}/{}", now(), proof_id);
+        self.settled_proofs.insert(key, INDEXED)?;
+
+        Ok(())
+    }
+
+    /// Delete the tasks and batches of proof requests settled longer than the retention period ago.
+    fn prune_settled_proofs(&self) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        let cutoff = format!(
+            "{:016x}",
+            now().saturating_sub(SETTLED_PROOF_RETENTION_SECS)
+        );
+        for key in self.settled_proofs.range(..cutoff.as_str()).keys() {
+            let key = key?;
+            let Some((_, proof_id)) = std::str::from_utf8(&key)?.split_once('/') else {
+                continue;
+            };
+            let prefix = proof_key(proof_id, "");
+            for index_key in self.proof_tasks.scan_prefix(&prefix).keys() {
+                let index_key = index_key?;
+                let id = &index_key[prefix.len()..];
+                self.tasks.remove(id)?;
+                self.batches.remove(id)?;
+                self.pending_tasks.remove(id)?;
+                self.running_tasks.remove(id)?;
+                self.proof_tasks.remove(&index_key)?;
+            }
+            self.settled_proofs.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    /// CPU cycles used by a requester this month and its monthly limit.
+    fn cycle_usage(&self, requester_address: &[u8]) -> Result<(i64, i64)> {
+        let requester = hex::encode(requester_address);
+        let key = format!("{}/{}", requester, month());
+        let used = Self::get::<i64>(&self.cpu_cycles, &key)?.unwrap_or_default();
+
+        Ok((used, config().cycle_limit(&requester)))
+    }
+
+    /// Set the cycles charged for a proof request and update its requester's usage this month.
+    /// Proofs that would take the requester over its limit are not charged.
+    fn modify_cpu_cycles(&self, proof_id: &str, cycles: i64) -> Result<ModifyCpuCyclesResponse> {
+        let _guard = self.lock.lock().unwrap();
+
+        let mut record = self.claimed_proof(proof_id)?;
+        let requester = hex::encode(&record.request.requester_address);
+        let key = format!("{}/{}", requester, month());
+
+        // A proof charged in an earlier month isn't part of this month's usage, so don't let
+        // replacing its charge take the usage below zero.
+        let others =
+            (Self::get::<i64>(&self.cpu_cycles, &key)?.unwrap_or_default() - record.cycles).max(0);
+        let used = others + cycles;
+        let limit = config().cycle_limit(&requester);
+        let exceeded_limit = used > limit;
+
+        // A proof over the limit is given up on, so the requester isn't charged for it.
+        record.cycles = if exceeded_limit { 0 } else { cycles };
+        Self::put(&self.cpu_cycles, &key, &(others + record.cycles))?;
+        Self::put(&self.claimed_proofs, proof_id, &record)?;
+
+        Ok(ModifyCpuCyclesResponse {
+            exceeded_limit,
+            used,
+            limit,
+        })
+    }
+}
+
+/// The `ClusterService` served by the master node.
+pub struct ClusterServer {
+    store: ClusterStore,
+}
+
+impl ClusterServer {
+    pub fn new(store: ClusterStore) -> Self {
+        Self { store }
+    }
+}
+
+impl ClusterServer {
+    /// Number of proof requests the cluster can take on, out of `cluster_max_proofs` worked on at
+    /// the same time.
+    pub fn available(&self) -> Result<usize> {
+        let max_proofs = config().cluster_max_proofs;
+        // Proofs dispatched to worker nodes are registered without tasks.
+        let in_progress = self
+            .store
+            .claimed_proofs_with_tasks()?
+            .iter()
+            .filter(|(_, tasks)| !tasks.is_empty())
+            .count();
+
+        Ok(max_proofs.saturating_sub(in_progress))
+    }
+
+    /// The CPU cycles a requester used this month and its monthly limit, if it has no cycles
+    /// left. Proof requests of such requesters are not claimed until the next month.
+    pub fn exhausted_cycle_budget(&self, requester_address: &[u8]) -> Result<Option<(i64, i64)>> {
+        let (used, limit) = self.store.cycle_usage(requester_address)?;
+        Ok((used >= limit).then_some((used, limit)))
+    }
+
+    /// Record a claimed proof request so that the cycles it uses can be charged to its requester.
+    pub fn register_proof(
+        &self,
+        proof_request: &ProofRequest,
+        requester_address: Vec<u8>,
+    ) -> Result<()> {
+        self.store.create_claimed_proof(CreateClaimedProofRequest {
+            proof_id: proof_request.proof_id.clone(),
+            program_artifact_id: proof_request.program_artifact_id.clone(),
+            stdin_artifact_id: proof_request.stdin_artifact_id.clone(),
+            proof_artifact_id: proof_request.proof_artifact_id.clone(),
+            requester_address,
+        })
+    }
+
+    /// Hand a registered proof request to the cluster by creating its controller task. Returns
+    /// the ID of the controller task. The task carries `traceparent`, so that the workers
+    /// continue the proof's trace.
+    pub fn submit_proof(&self, proof_request: &ProofRequest, traceparent: &str) -> Result<String> {
+        let metadata = serde_json::json!({
+            "mode": proof_request.mode,
+            "traceparent": traceparent,
+        })
+        .to_string();
+        self.store.create_task(
+            None,
+            &proof_request.proof_id,
+            TaskRequestData {
+                task_type: TaskType::Sp1ControllerExecute as i32,
+                input_artifact_ids: vec![
+                    proof_request.program_artifact_id.clone(),
+                    proof_request.stdin_artifact_id.clone(),
+                ],
+                output_artifact_ids: vec![proof_request.proof_artifact_id.clone()],
+                metadata,
+                weight: CONTROLLER_TASK_WEIGHT,
+            },
+        )
+    }
+
+    /// Requeue tasks whose worker stopped bumping them.
+    pub fn expire_dropped_tasks(&self) -> Result<()> {
+        self.store.expire_dropped_tasks()
+    }
+
+    /// Delete the tasks of proof requests settled a while ago.
+    pub fn prune(&self) -> Result<()> {
+        self.store.prune_settled_proofs()
+    }
+
+    /// Claimed proof requests whose proof is ready to be fulfilled.
+    pub fn fulfillable_proofs(&self) -> Result<Vec<String>> {
+        self.store.fulfillable_proofs()
+    }
+
+    /// Claimed proof requests the cluster failed to prove.
+    pub fn failed_proofs(&self) -> Result<Vec<String>> {
+        self.store.failed_proofs()
+    }
+
+    /// Record the outcome of a claimed proof request on the network.
+    pub fn set_proof_status(&self, proof_id: &str, status: ProofRequestStatus) -> Result<()> {
+        self.store.update_proof_status(proof_id, status)
+    }
+}
+
+#[twirp::async_trait::async_trait]
+impl ClusterService for ClusterServer {
+    async fn create_artifact(
+        &self,
+        _ctx: Context,
+        req: CreateArtifactRequest,
+    ) -> Result<CreateArtifactResponse, TwirpErrorResponse> {
+        let artifact = self
+            .store
+            .create_artifact(&req.label, req.expiry)
+            .map_err(internal)?;
+
+        Ok(CreateArtifactResponse {
+            artifact: Some(artifact),
+        })
+    }
+
+    async fn create_artifact_batch(
+        &self,
+        _ctx: Context,
+        req: CreateArtifactBatchRequest,
+    ) -> Result<CreateArtifactBatchResponse, TwirpErrorResponse> {
+        let artifacts = (0..req.count)
+            .map(|_| self.store.create_artifact(&req.label, req.expiry))
+            .collect::<Result<Vec<_>>>()
+            .map_err(internal)?;
+
+        Ok(CreateArtifactBatchResponse { artifacts })
+    }
+
+    async fn create_task(
+        &self,
+        _ctx: Context,
+        req: CreateTaskRequest,
+    ) -> Result<CreateTaskResponse, TwirpErrorResponse> {
+        let data = req
+            .data
+            .ok_or_else(|| twirp::invalid_argument("task data is required"))?;
+        let id = self
+            .store
+            .create_task(req.parent_id, &req.proof_id, data)
+            .map_err(internal)?;
+
+        Ok(CreateTaskResponse { id })
+    }
+
+    async fn create_task_batch(
+        &self,
+        _ctx: Context,
+        req: CreateTaskBatchRequest,
+    ) -> Result<CreateTaskBatchResponse, TwirpErrorResponse> {
+        let id = self
+            .store
+            .create_task_batch(req.parent_id, &req.proof_id, req.data)
+            .map_err(internal)?;
+
+        Ok(CreateTaskBatchResponse { id })
+    }
+
+    async fn pop_tasks(
+        &self,
+        _ctx: Context,
+        req: PopTasksRequest,
+    ) -> Result<PopTasksResponse, TwirpErrorResponse> {
+        let worker_type = match WorkerType::try_from(req.worker_type) {
+            Ok(WorkerType::Unspecified) | Err(_) => {
+                return Err(twirp::invalid_argument(format!(
+                    "Invalid worker type: {}",
+                    req.worker_type
+                )))
+            }
+            Ok(worker_type) => worker_type,
+        };
+        let tasks = self
+            .store
+            .pop_tasks(worker_type, req.max_weight)
+            .map_err(internal)?;
+
+        Ok(PopTasksResponse { tasks })
+    }
+
+    async fn bump_tasks(
+        &self,
+        _ctx: Context,
+        req: BumpTasksRequest,
+    ) -> Result<BumpTasksResponse, TwirpErrorResponse> {
+        self.store.bump_tasks(&req.task_ids).map_err(internal)?;
+
+        Ok(BumpTasksResponse {})
+    }
+
+    async fn fetch_task_status(
+        &self,
+        _ctx: Context,
+        req: FetchTaskStatusRequest,
+    ) -> Result<FetchTaskStatusResponse, TwirpErrorResponse> {
+        let status = self
+            .store
+            .task_status(&req.id)
+            .map_err(|e| twirp::not_found(e.to_string()))?;
+
+        Ok(FetchTaskStatusResponse { status })
+    }
+
+    async fn fetch_task_batch_status(
+        &self,
+        _ctx: Context,
+        req: FetchTaskBatchStatusRequest,
+    ) -> Result<FetchTaskBatchStatusResponse, TwirpErrorResponse> {
+        let task_ids = self
+            .store
+            .batch(&req.id)
+            .map_err(internal)?
+            .ok_or_else(|| twirp::not_found(format!("Task batch '{}' not found", req.id)))?;
+        let statuses = task_ids
+            .iter()
+            .map(|id| self.store.task_status(id))
+            .collect::<Result<Vec<_>>>()
+            .map_err(internal)?;
+
+        Ok(FetchTaskBatchStatusResponse { statuses })
+    }
+
+    async fn fetch_task_statuses(
+        &self,
+        _ctx: Context,
+        req: FetchTaskStatusesRequest,
+    ) -> Result<FetchTaskStatusesResponse, TwirpErrorResponse> {
+        let statuses = req
+            .ids
+            .iter()
+            .map(|id| self.store.task_status(id))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| twirp::not_found(e.to_string()))?;
+
+        Ok(FetchTaskStatusesResponse { statuses })
+    }
+
+    async fn update_task_status(
+        &self,
+        _ctx: Context,
+        req: UpdateTaskStatusRequest,
+    ) -> Result<UpdateTaskStatusResponse, TwirpErrorResponse> {
+        let status = TaskStatus::try_from(req.status)
+            .map_err(|_| twirp::invalid_argument(format!("Invalid task status: {}", req.status)))?;
+        self.store
+            .update_task_status(&req.id, status)
+            .map_err(internal)?;
+
+        Ok(UpdateTaskStatusResponse {})
+    }
+
+    async fn expire_dropped_tasks(
+        &self,
+        _ctx: Context,
+        _req: ExpireDroppedTasksRequest,
+    ) -> Result<ExpireDroppedTasksResponse, TwirpErrorResponse> {
+        self.store.expire_dropped_tasks().map_err(internal)?;
+
+        Ok(ExpireDroppedTasksResponse {})
+    }
+
+    async fn get_fulfillable_proof_requests(
+        &self,
+        _ctx: Context,
+        _req: GetFulfillableProofRequestsRequest,
+    ) -> Result<GetFulfillableProofRequestsResponse, TwirpErrorResponse> {
+        let proof_ids = self.store.fulfillable_proofs().map_err(internal)?;
+
+        Ok(GetFulfillableProofRequestsResponse { proof_ids })
+    }
+
+    async fn get_failed_proof_requests(
+        &self,
+        _ctx: Context,
+        _req: GetFailedProofRequestsRequest,
+    ) -> Result<GetFailedProofRequestsResponse, TwirpErrorResponse> {
+        let proof_ids = self.store.failed_proofs().map_err(internal)?;
+
+        Ok(GetFailedProofRequestsResponse { proof_ids })
+    }
+
+    async fn create_claimed_proof(
+        &self,
+        _ctx: Context,
+        req: CreateClaimedProofRequest,
+    ) -> Result<CreateClaimedProofResponse, TwirpErrorResponse> {
+        self.store.create_claimed_proof(req).map_err(internal)?;
+
+        Ok(CreateClaimedProofResponse {})
+    }
+
+    async fn update_proof_status(
+        &self,
+        _ctx: Context,
+        req: UpdateProofStatusRequest,
+    ) -> Result<UpdateProofStatusResponse, TwirpErrorResponse> {
+        let status = ProofRequestStatus::try_from(req.status).map_err(|_| {
+            twirp::invalid_argument(format!("Invalid proof status: {}", req.status))
+        })?;
+        self.store
+            .update_proof_status(&req.proof_id, status)
+            .map_err(internal)?;
+
+        Ok(UpdateProofStatusResponse {})
+    }
+
+    async fn modify_cpu_cycles(
+        &self,
+        _ctx: Context,
+        req: ModifyCpuCyclesRequest,
+    ) -> Result<ModifyCpuCyclesResponse, TwirpErrorResponse> {
+        self.store
+            .modify_cpu_cycles(&req.proof_id, req.cycles)
+            .map_err(internal)
+    }
+}
+
+/// Serve the `ClusterService` under `/twirp` and the master node's metrics under `/metrics` on
+/// `cluster_server_port`.
+pub async fn start_cluster_server(cluster: Arc<ClusterServer>) -> Result<()> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], config().cluster_server_port));
+
+    let twirp_routes = axum::Router::new().nest(SERVICE_FQN, router(cluster));
+    let app = axum::Router::new()
+        .nest("/twirp", twirp_routes)
+        .route("/metrics", axum::routing::get(metrics_handler))
+        .fallback(twirp::server::not_found_handler);
+
+    info!("Cluster service running on {}.", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn open_store() -> (tempfile::TempDir, ClusterStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ClusterStore::open(dir.path().to_str().unwrap()).unwrap();
+        (dir, store)
+    }
+
+    fn claim(store: &ClusterStore, proof_id: &str, requester: u8) {
+        store
+            .create_claimed_proof(CreateClaimedProofRequest {
+                proof_id: proof_id.to_string(),
+                requester_address: vec![requester; 20],
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    fn add_task(store: &ClusterStore, proof_id: &str, task_type: TaskType, weight: u32) -> String {
+        let data = TaskRequestData {
+            task_type: task_type as i32,
+            weight,
+            ..Default::default()
+        };
+        store.create_task(None, proof_id, data).unwrap()
+    }
+
+    fn ids(tasks: &[Task]) -> Vec<&str> {
+        tasks.iter().map(|task| task.id.as_str()).collect()
+    }
+
+    #[test]
+    fn pop_tasks_cancels_tasks_of_settled_proofs() {
+        let (_dir, store) = open_store();
+        let task = add_task(&store, "unclaimed", TaskType::Sp1CommitShard, 4);
+
+        assert!(store.pop_tasks(WorkerType::Gpu, 4).unwrap().is_empty());
+        assert_eq!(
+            store.task_status(&task).unwrap(),
+            TaskStatus::FailedFatal as i32
+        );
+    }
+
+    #[test]
+    fn retryable_failures_are_retried_until_out_of_attempts() {
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x11);
+        let task = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+
+        for _ in 1..MAX_TASK_ATTEMPTS {
+            store.pop_tasks(WorkerType::Gpu, 4).unwrap();
+            store
+                .update_task_status(&task, TaskStatus::FailedRetryable)
+                .unwrap();
+            assert_eq!(
+                store.task_status(&task).unwrap(),
+                TaskStatus::Pending as i32
+            );
+        }
+        store
+            .update_task_status(&task, TaskStatus::FailedRetryable)
+            .unwrap();
+        assert_eq!(
+            store.task_status(&task).unwrap(),
+            TaskStatus::FailedFatal as i32
+        );
+
+        // Finished tasks keep their status.
+        store
+            .update_task_status(&task, TaskStatus::Succeeded)
+            .unwrap();
+        assert_eq!(
+            store.task_status(&task).unwrap(),
+            TaskStatus::FailedFatal as i32
+        );
+    }
+
+    #[test]
+    fn accepts_by_worker_type() {
+        assert!(accepts(WorkerType::Cpu, TaskType::Sp1ControllerExecute));
+        assert!(accepts(WorkerType::Cpu, TaskType::Sp1ControllerPlonk));
+        assert!(!accepts(WorkerType::Cpu, TaskType::Sp1CommitShard));
+        assert!(accepts(WorkerType::Gpu, TaskType::Sp1CommitShard));
+        assert!(accepts(WorkerType::Gpu, TaskType::Sp1ControllerWrap));
+        assert!(!accepts(WorkerType::Gpu, TaskType::Sp1ControllerExecute));
+        assert!(accepts(WorkerType::All, TaskType::Sp1CommitShard));
+        assert!(accepts(WorkerType::All, TaskType::Sp1ControllerExecute));
+        assert!(!accepts(WorkerType::Modal, TaskType::Sp1CommitShard));
+        assert!(!accepts(
+            WorkerType::Unspecified,
+            TaskType::Sp1ControllerExecute
+        ));
+    }
+
+    #[test]
+    fn pop_tasks_fills_the_weight_budget() {
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x11);
+        let first = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+        let second = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+        let third = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+
+        let popped = store.pop_tasks(WorkerType::Gpu, 8).unwrap();
+        assert_eq!(ids(&popped), vec![first.as_str(), second.as_str()]);
+        assert_eq!(
+            store.task_status(&first).unwrap(),
+            TaskStatus::Running as i32
+        );
+        assert_eq!(
+            store.task_status(&third).unwrap(),
+            TaskStatus::Pending as i32
+        );
+
+        let popped = store.pop_tasks(WorkerType::Gpu, 8).unwrap();
+        assert_eq!(ids(&popped), vec![third.as_str()]);
+        assert!(store.pop_tasks(WorkerType::Gpu, 8).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pop_tasks_skips_tasks_over_the_budget() {
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x11);
+        let heavy = add_task(&store, "proof", TaskType::Sp1CommitShard, 8);
+        let light = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+
+        let popped = store.pop_tasks(WorkerType::Gpu, 4).unwrap();
+        assert_eq!(ids(&popped), vec![light.as_str()]);
+        assert_eq!(
+            store.task_status(&heavy).unwrap(),
+            TaskStatus::Pending as i32
+        );
+    }
+
+    #[test]
+    fn pop_tasks_filters_by_worker_type() {
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x11);
+        let controller = add_task(
+            &store,
+            "proof",
+            TaskType::Sp1ControllerExecute,
+            CONTROLLER_TASK_WEIGHT,
+        );
+        let shard = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+
+        // Controllers are weightless, so they are popped even without room for proving tasks.
+        let popped = store.pop_tasks(WorkerType::Cpu, 0).unwrap();
+        assert_eq!(ids(&popped), vec![controller.as_str()]);
+        let popped = store.pop_tasks(WorkerType::Gpu, 4).unwrap();
+        assert_eq!(ids(&popped), vec![shard.as_str()]);
+    }
+
+    #[test]
+    fn bump_tasks_skips_unknown_tasks() {
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x11);
+        let task = add_task(&store, "proof", TaskType::Sp1CommitShard, 4);
+        let mut record = store.task(&task).unwrap();
+        record.heartbeat_at = 0;
+        ClusterStore::put(&store.tasks, &task, &record).unwrap();
+
+        store
+            .bump_tasks(&["pruned".to_string(), task.clone()])
+            .unwrap();
+        assert!(store.task(&task).unwrap().heartbeat_at > 0);
+    }
+
+    #[test]
+    fn modify_cpu_cycles_charges_the_requester() {
+        test_config();
+        let (_dir, store) = open_store();
+        claim(&store, "first", 0x11);
+        claim(&store, "second", 0x11);
+
+        let response = store.modify_cpu_cycles("first", 600).unwrap();
+        assert!(!response.exceeded_limit);
+        assert_eq!((response.used, response.limit), (600, 1000));
+
+        // Charging a proof again replaces its previous charge.
+        let response = store.modify_cpu_cycles("first", 700).unwrap();
+        assert!(!response.exceeded_limit);
+        assert_eq!(response.used, 700);
+
+        // A proof over the limit isn't charged.
+        let response = store.modify_cpu_cycles("second", 400).unwrap();
+        assert!(response.exceeded_limit);
+        assert_eq!(response.used, 1100);
+        assert_eq!(store.cycle_usage(&[0x11; 20]).unwrap(), (700, 1000));
+        assert_eq!(store.claimed_proof("second").unwrap().cycles, 0);
+    }
+
+    #[test]
+    fn modify_cpu_cycles_uses_requester_limits() {
+        test_config();
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x22);
+
+        let response = store.modify_cpu_cycles("proof", 600).unwrap();
+        assert!(response.exceeded_limit);
+        assert_eq!(response.limit, 500);
+        assert_eq!(store.cycle_usage(&[0x22; 20]).unwrap(), (0, 500));
+
+        let response = store.modify_cpu_cycles("proof", 400).unwrap();
+        assert!(!response.exceeded_limit);
+        assert_eq!(store.cycle_usage(&[0x22; 20]).unwrap(), (400, 500));
+    }
+
+    #[test]
+    fn modify_cpu_cycles_after_a_month_reset() {
+        test_config();
+        let (_dir, store) = open_store();
+        claim(&store, "proof", 0x11);
+        store.modify_cpu_cycles("proof", 600).unwrap();
+
+        // The proof was charged last month, so this month's usage doesn't include it.
+        store.cpu_cycles.clear().unwrap();
+
+        let response = store.modify_cpu_cycles("proof", 300).unwrap();
+        assert!(!response.exceeded_limit);
+        assert_eq!(response.used, 300);
+        assert_eq!(store.cycle_usage(&[0x11; 20]).unwrap(), (300, 1000));
+    }
+}
@@ -0,0 +1,439 @@
+//! Configuration of the master node, loaded once at startup from command-line flags, environment
+//! variables and an optional TOML file, in that order of precedence.
+
+use std::{fs, str::FromStr, sync::OnceLock, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use prover_common::telemetry::{LogFormat, OtelExporter, TelemetryConfig};
+use serde::Deserialize;
+
+use crate::listener::DispatchMode;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Monthly CPU cycle limit of a single requester, written `<address>=<limit>`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RequesterCycleLimit {
+    /// Hex encoded address, without `0x`.
+    pub requester: String,
+    pub limit: i64,
+}
+
+impl FromStr for RequesterCycleLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requester, limit) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <address>=<limit>, got '{}'", s))?;
+        let requester = requester.trim().trim_start_matches("0x").to_lowercase();
+        if hex::decode(&requester).map_or(true, |address| address.len() != 20) {
+            return Err(format!("invalid requester address in '{}'", s));
+        }
+        let limit = limit
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid cycle limit in '{}'", s))?;
+
+        Ok(Self { requester, limit })
+    }
+}
+
+impl TryFrom<String> for RequesterCycleLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Settings of the master node as given by a single source. Settings that are not given fall
+/// back to the next source, and then to their default.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(
+    version,
+    about = "Claims proof requests on the prover network and proves them."
+)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    /// TOML file with settings, named like the long flags with underscores.
+    #[arg(long, env = "CONFIG_FILE")]
+    #[serde(skip)]
+    config: Option<String>,
+    /// secp256k1 private key the master node claims and fulfills proofs with.
+    #[arg(long, env = "SP1_PRIVATE_KEY", hide_env_values = true)]
+    sp1_private_key: Option<String>,
+    /// URLs of the worker nodes.
+    #[arg(long, env = "WORKER_NODE_ENDPOINTS", value_delimiter = ',')]
+    worker_node_endpoints: Option<Vec<String>>,
+    /// URL of a single worker node, used when `WORKER_NODE_ENDPOINTS` is not set.
+    #[arg(long, env = "WORKER_NODE_ENDPOINT", hide = true)]
+    worker_node_endpoint: Option<String>,
+    /// How claimed proof requests are proven [default: workers].
+    #[arg(long, env = "DISPATCH_MODE")]
+    dispatch_mode: Option<DispatchMode>,
+    /// Directory of the local job ledger [default: data/ledger].
+    #[arg(long, env = "LEDGER_PATH")]
+    ledger_path: Option<String>,
+    /// Directory of the cluster service database [default: data/cluster].
+    #[arg(long, env = "CLUSTER_DB_PATH")]
+    cluster_db_path: Option<String>,
+    /// Port of the cluster service and the metrics endpoint [default: 3000].
+    #[arg(long, env = "CLUSTER_SERVER_PORT")]
+    cluster_server_port: Option<u16>,
+    /// Max proofs worked on by the cluster at the same time [default: 1].
+    #[arg(long, env = "CLUSTER_MAX_PROOFS")]
+    cluster_max_proofs: Option<usize>,
+    /// Max CPU cycles per requester per month, 0 for unlimited [default: 0].
+    #[arg(long, env = "MONTHLY_CYCLE_LIMIT")]
+    monthly_cycle_limit: Option<i64>,
+    /// Per-requester overrides of the monthly cycle limit, as `<address>=<limit>`.
+    #[arg(long, env = "REQUESTER_CYCLE_LIMITS", value_delimiter = ',')]
+    requester_cycle_limits: Option<Vec<RequesterCycleLimit>>,
+    /// Seconds to wait for in-flight proofs on shutdown before unclaiming them [default: 300].
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Log output format [default: json].
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Where spans are exported to [default: none].
+    #[arg(long, env = "OTEL_EXPORTER")]
+    otel_exporter: Option<OtelExporter>,
+    /// OTLP gRPC endpoint spans are sent to [default: http://localhost:4317].
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otel_exporter_otlp_endpoint: Option<String>,
+    /// File spans are appended to [default: traces.jsonl].
+    #[arg(long, env = "OTEL_EXPORTER_FILE")]
+    otel_exporter_file: Option<String>,
+    /// Share of proof requests whose traces are exported, between 0 and 1 [default: 1].
+    #[arg(long, env = "OTEL_SAMPLING_RATIO")]
+    otel_sampling_ratio: Option<f64>,
+    /// Service name in exported traces [default: sp1-prover-master].
+    #[arg(long, env = "OTEL_SERVICE_NAME")]
+    otel_service_name: Option<String>,
+}
+
+impl Settings {
+    /// Fill the settings that are not given from another source.
+    fn or(self, other: Self) -> Self {
+        Self {
+            config: self.config.or(other.config),
+            sp1_private_key: self.sp1_private_key.or(other.sp1_private_key),
+            worker_node_endpoints: self.worker_node_endpoints.or(other.worker_node_endpoints),
+            worker_node_endpoint: self.worker_node_endpoint.or(other.worker_node_endpoint),
+            dispatch_mode: self.dispatch_mode.or(other.dispatch_mode),
+            ledger_path: self.ledger_path.or(other.ledger_path),
+            cluster_db_path: self.cluster_db_path.or(other.cluster_db_path),
+            cluster_server_port: self.cluster_server_port.or(other.cluster_server_port),
+            cluster_max_proofs: self.cluster_max_proofs.or(other.cluster_max_proofs),
+            monthly_cycle_limit: self.monthly_cycle_limit.or(other.monthly_cycle_limit),
+            requester_cycle_limits: self.requester_cycle_limits.or(other.requester_cycle_limits),
+            shutdown_timeout_secs: self.shutdown_timeout_secs.or(other.shutdown_timeout_secs),
+            log_format: self.log_format.or(other.log_format),
+            otel_exporter: self.otel_exporter.or(other.otel_exporter),
+            otel_exporter_otlp_endpoint: self
+                .otel_exporter_otlp_endpoint
+                .or(other.otel_exporter_otlp_endpoint),
+            otel_exporter_file: self.otel_exporter_file.or(other.otel_exporter_file),
+            otel_sampling_ratio: self.otel_sampling_ratio.or(other.otel_sampling_ratio),
+            otel_service_name: self.otel_service_name.or(other.otel_service_name),
+        }
+    }
+}
+
+/// Validated configuration of the master node.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub sp1_private_key: String,
+    pub worker_node_endpoints: Vec<String>,
+    pub dispatch_mode: DispatchMode,
+    pub ledger_path: String,
+    pub cluster_db_path: String,
+    pub cluster_server_port: u16,
+    pub cluster_max_proofs: usize,
+    pub monthly_cycle_limit: i64,
+    pub requester_cycle_limits: Vec<RequesterCycleLimit>,
+    pub shutdown_timeout: Duration,
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Load the configuration from the command line, the environment and the TOML file given by
+    /// `--config` or `CONFIG_FILE`.
+    fn load() -> Result<Self> {
+        let args = Settings::parse();
+        let file = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Failed to parse config file {}", path))?
+            }
+            None => Settings::default(),
+        };
+
+        Self::resolve(args.or(file))
+    }
+
+    /// Apply the defaults and check the settings, reporting every invalid one at once.
+    fn resolve(settings: Settings) -> Result<Self> {
+        let mut errors = Vec::new();
+
+        let sp1_private_key = settings.sp1_private_key.unwrap_or_default();
+        let key = sp1_private_key.trim_start_matches("0x");
+        if key.is_empty() {
+            errors.push("SP1_PRIVATE_KEY must be set".to_string());
+        } else if hex::decode(key).map_or(true, |key| key.len() != 32) {
+            errors.push("SP1_PRIVATE_KEY must be 32 hex encoded bytes".to_string());
+        }
+
+        let worker_node_endpoints = settings
+            .worker_node_endpoints
+            .or(settings.worker_node_endpoint.map(|endpoint| vec![endpoint]))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|endpoint| endpoint.trim().to_string())
+            .filter(|endpoint| !endpoint.is_empty())
+            .collect::<Vec<_>>();
+        // Proofs dispatched to the cluster are pulled by the workers, which need not be listed.
+        let dispatch_mode = settings.dispatch_mode.unwrap_or(DispatchMode::Workers);
+        if dispatch_mode == DispatchMode::Workers && worker_node_endpoints.is_empty() {
+            errors.push("WORKER_NODE_ENDPOINTS or WORKER_NODE_ENDPOINT must be set".to_string());
+        }
+        for endpoint in &worker_node_endpoints {
+            if reqwest::Url::parse(endpoint).is_err() {
+                errors.push(format!("Invalid worker node endpoint: {}", endpoint));
+            }
+        }
+
+        let cluster_max_proofs = settings.cluster_max_proofs.unwrap_or(1);
+        if cluster_max_proofs == 0 {
+            errors.push("CLUSTER_MAX_PROOFS must be at least 1".to_string());
+        }
+
+        let monthly_cycle_limit = settings.monthly_cycle_limit.unwrap_or(0);
+        if monthly_cycle_limit < 0 {
+            errors.push("MONTHLY_CYCLE_LIMIT must not be negative".to_string());
+        }
+
+        let otel_sampling_ratio = settings.otel_sampling_ratio.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&otel_sampling_ratio) {
+            errors.push("OTEL_SAMPLING_RATIO must be between 0 and 1".to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Invalid configuration:\n  - {}",
+                errors.join("\n  - ")
+            ));
+        }
+
+        Ok(Self {
+            sp1_private_key,
+            worker_node_endpoints,
+            dispatch_mode,
+            ledger_path: settings
+                .ledger_path
+                .unwrap_or_else(|| "data/ledger".to_string()),
+            cluster_db_path: settings
+                .cluster_db_path
+                .unwrap_or_else(|| "data/cluster".to_string()),
+            cluster_server_port: settings.cluster_server_port.unwrap_or(3000),
+            cluster_max_proofs,
+            monthly_cycle_limit,
+            requester_cycle_limits: settings.requester_cycle_limits.unwrap_or_default(),
+            shutdown_timeout: Duration::from_secs(settings.shutdown_timeout_secs.unwrap_or(300)),
+            telemetry: TelemetryConfig {
+                log_format: settings.log_format.unwrap_or(LogFormat::Json),
+                otel_exporter: settings.otel_exporter.unwrap_or(OtelExporter::None),
+                otel_exporter_otlp_endpoint: settings
+                    .otel_exporter_otlp_endpoint
+                    .unwrap_or_else(|| "http://localhost:4317".to_string()),
+                otel_exporter_file: settings
+                    .otel_exporter_file
+                    .unwrap_or_else(|| "traces.jsonl".to_string()),
+                otel_sampling_ratio,
+                otel_service_name: settings
+                    .otel_service_name
+                    .unwrap_or_else(|| "sp1-prover-master".to_string()),
+            },
+        })
+    }
+
+    /// Monthly CPU cycle limit of a hex encoded requester address, from its entry in
+    /// `requester_cycle_limits` or else `monthly_cycle_limit`. Zero means unlimited.
+    pub fn cycle_limit(&self, requester: &str) -> i64 {
+        let limit = self
+            .requester_cycle_limits
+            .iter()
+            .find(|limit| limit.requester.eq_ignore_ascii_case(requester))
+            .map_or(self.monthly_cycle_limit, |limit| limit.limit);
+
+        if limit > 0 {
+            limit
+        } else {
+            i64::MAX
+        }
+    }
+}
+
+/// Load and validate the configuration. Called once at startup, before anything reads it.
+pub fn init() -> Result<&'static Config> {
+    let config = Config::load()?;
+
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The configuration loaded at startup.
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("Configuration is not loaded")
+}
+
+/// The configuration used by tests: a monthly cycle limit of 1000, lowered to 500 for the
+/// requester `0x2222…22`.
+#[cfg(test)]
+pub fn test_config() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        let requester_limit = format!("{}=500", "22".repeat(20)).parse().unwrap();
+        Config::resolve(Settings {
+            sp1_private_key: Some(tests::PRIVATE_KEY.to_string()),
+            worker_node_endpoints: Some(vec!["http://localhost:8080".to_string()]),
+            monthly_cycle_limit: Some(1000),
+            requester_cycle_limits: Some(vec![requester_limit]),
+            ..Default::default()
+        })
+        .unwrap()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub const PRIVATE_KEY: &str =
+        "0x1111111111111111111111111111111111111111111111111111111111111111";
+
+    fn valid() -> Settings {
+        Settings {
+            sp1_private_key: Some(PRIVATE_KEY.to_string()),
+            worker_node_endpoints: Some(vec!["http://localhost:8080".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resolve_applies_defaults() {
+        let config = Config::resolve(valid()).unwrap();
+        assert_eq!(config.dispatch_mode, DispatchMode::Workers);
+        assert_eq!(config.cluster_server_port, 3000);
+        assert_eq!(config.cluster_max_proofs, 1);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(300));
+        assert_eq!(config.telemetry.log_format, LogFormat::Json);
+        assert_eq!(config.telemetry.otel_service_name, "sp1-prover-master");
+    }
+
+    #[test]
+    fn resolve_reports_every_error() {
+        let settings = Settings {
+            sp1_private_key: Some("0x1234".to_string()),
+            cluster_max_proofs: Some(0),
+            monthly_cycle_limit: Some(-1),
+            otel_sampling_ratio: Some(1.5),
+            ..Default::default()
+        };
+        let error = Config::resolve(settings).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid configuration:\n"));
+        for expected in [
+            "SP1_PRIVATE_KEY must be 32 hex encoded bytes",
+            "WORKER_NODE_ENDPOINTS or WORKER_NODE_ENDPOINT must be set",
+            "CLUSTER_MAX_PROOFS must be at least 1",
+            "MONTHLY_CYCLE_LIMIT must not be negative",
+            "OTEL_SAMPLING_RATIO must be between 0 and 1",
+        ] {
+            assert!(
+                error.contains(expected),
+                "missing '{}' in {}",
+                expected,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_rejects_invalid_endpoints() {
+        let settings = Settings {
+            worker_node_endpoints: Some(vec!["not a url".to_string()]),
+            ..valid()
+        };
+        let error = Config::resolve(settings).unwrap_err().to_string();
+        assert!(error.contains("Invalid worker node endpoint: not a url"));
+    }
+
+    #[test]
+    fn earlier_sources_take_precedence() {
+        let args = Settings {
+            cluster_server_port: Some(4000),
+            ..Default::default()
+        };
+        let file = Settings {
+            cluster_server_port: Some(5000),
+            ledger_path: Some("ledger".to_string()),
+            ..valid()
+        };
+        let config = Config::resolve(args.or(file)).unwrap();
+        assert_eq!(config.cluster_server_port, 4000);
+        assert_eq!(config.ledger_path, "ledger");
+    }
+
+    #[test]
+    fn cluster_mode_needs_no_endpoints() {
+        let settings = Settings {
+            worker_node_endpoints: None,
+            dispatch_mode: Some(DispatchMode::Cluster),
+            ..valid()
+        };
+        let config = Config::resolve(settings).unwrap();
+        assert!(config.worker_node_endpoints.is_empty());
+    }
+
+    #[test]
+    fn single_endpoint_is_a_fallback() {
+        let settings = Settings {
+            worker_node_endpoints: None,
+            worker_node_endpoint: Some("http://worker:8080".to_string()),
+            ..valid()
+        };
+        let config = Config::resolve(settings).unwrap();
+        assert_eq!(config.worker_node_endpoints, vec!["http://worker:8080"]);
+    }
+
+    #[test]
+    fn cycle_limits() {
+        let requester = "ab".repeat(20);
+        let requester_limit = format!("0x{}=10", requester.to_uppercase())
+            .parse()
+            .unwrap();
+        let settings = Settings {
+            monthly_cycle_limit: Some(1000),
+            requester_cycle_limits: Some(vec![requester_limit]),
+            ..valid()
+        };
+        let config = Config::resolve(settings).unwrap();
+        assert_eq!(config.cycle_limit(&requester), 10);
+        assert_eq!(config.cycle_limit(&"cd".repeat(20)), 1000);
+
+        let config = Config::resolve(valid()).unwrap();
+        assert_eq!(config.cycle_limit(&requester), i64::MAX);
+    }
+
+    #[test]
+    fn requester_cycle_limit_parsing() {
+        assert!("abc=10".parse::<RequesterCycleLimit>().is_err());
+        assert!("ab".repeat(20).parse::<RequesterCycleLimit>().is_err());
+        assert!(format!("{}=many", "ab".repeat(20))
+            .parse::<RequesterCycleLimit>()
+            .is_err());
+    }
+}
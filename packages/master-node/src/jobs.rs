@@ -0,0 +1,98 @@
+//! Client side of the worker node's asynchronous job API.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::warn;
+use prover_common::jobs::Job;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use crate::listener::ProofRequest;
+use crate::telemetry::TraceContext;
+
+/// How often a dispatched job is polled for its status.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a worker may stay unreachable before its job is given up on.
+const WORKER_UNREACHABLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// The longest wait between status polls of an unreachable worker.
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+struct SubmitProofResponse {
+    job_id: String,
+}
+
+/// Submit a proof request to a worker and return the job ID. The worker continues the given trace.
+pub async fn submit_job(
+    http_client: &Client,
+    endpoint: &str,
+    proof_request: &ProofRequest,
+    trace: &TraceContext,
+) -> Result<String> {
+    let response = http_client
+        .post(format!("{}/prove", endpoint))
+        .header("traceparent", trace.traceparent())
+        .json(proof_request)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SubmitProofResponse>()
+        .await?;
+
+    Ok(response.job_id)
+}
+
+/// Fetch the current state of a job from a worker.
+pub async fn fetch_job(http_client: &Client, endpoint: &str, job_id: &str) -> Result<Job> {
+    let job = http_client
+        .get(format!("{}/jobs/{}", endpoint, job_id))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Job>()
+        .await?;
+
+    Ok(job)
+}
+
+/// Poll a job until it reaches a terminal state.
+///
+/// Gives up right away if the worker no longer knows the job, and only after
+/// `WORKER_UNREACHABLE_TIMEOUT` if the worker can't be reached, backing off between polls.
+pub async fn wait_for_job(http_client: &Client, endpoint: &str, job_id: &str) -> Result<Job> {
+    let mut unreachable_since = None;
+    let mut interval = JOB_POLL_INTERVAL;
+    loop {
+        match fetch_job(http_client, endpoint, job_id).await {
+            Ok(job) if job.status.is_finished() => return Ok(job),
+            Ok(_) => {
+                unreachable_since = None;
+                interval = JOB_POLL_INTERVAL;
+            }
+            Err(e) if is_not_found(&e) => {
+                return Err(e.context(format!("Job '{}' is gone from '{}'", job_id, endpoint)));
+            }
+            Err(e) => {
+                let since = *unreachable_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= WORKER_UNREACHABLE_TIMEOUT {
+                    return Err(e);
+                }
+                warn!("Failed to poll job '{}' on '{}': {:?}", job_id, endpoint, e);
+                interval = (interval * 2).min(MAX_POLL_BACKOFF);
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Whether a request failed because the worker answered 404 Not Found.
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        == Some(StatusCode::NOT_FOUND)
+}
@@ -0,0 +1,318 @@
+//! On-disk ledger of every proof request claimed by the master node.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+
+use crate::listener::ProofRequest;
+
+/// The status of a claimed proof request as seen by the master node.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerStatus {
+    /// Claimed on the network but not yet handed to a worker.
+    Claimed,
+    /// Accepted by a worker as a job.
+    Dispatched,
+    /// Generated and fulfilled by a worker.
+    Done,
+    /// The worker reported the job as failed.
+    Failed,
+    /// Unclaimed on the network by the master node.
+    Unclaimed,
+}
+
+/// A status change of a ledger entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusTransition {
+    pub status: LedgerStatus,
+    /// Unix timestamp of the transition, in seconds.
+    pub at: u64,
+}
+
+/// Everything the master node knows about a claimed proof request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub proof_request: ProofRequest,
+    /// Unix timestamp of the claim, in seconds.
+    pub claimed_at: u64,
+    /// Endpoint of the worker the proof request was dispatched to.
+    pub worker: Option<String>,
+    /// ID of the job on the worker.
+    pub job_id: Option<String>,
+    pub status: LedgerStatus,
+    pub transitions: Vec<StatusTransition>,
+    /// The proving time reported by the worker once the proof was fulfilled.
+    pub proving_seconds: Option<u64>,
+    /// Why the proof request failed or was unclaimed.
+    pub error: Option<String>,
+}
+
+impl LedgerEntry {
+    /// Whether the proof request still needs the master node's attention.
+    pub fn is_in_flight(&self) -> bool {
+        matches!(self.status, LedgerStatus::Claimed | LedgerStatus::Dispatched)
+    }
+}
+
+/// How long finished proof requests are kept in the ledger, in seconds.
+const LEDGER_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Value of the entries of the in-flight index, only their keys matter.
+const IN_FLIGHT_MARKER: &[u8] = &[];
+
+/// Ledger of claimed proof requests, keyed by proof ID and backed by sled.
+pub struct Ledger {
+    proofs: sled::Tree,
+    /// IDs of the proof requests in flight, so they can be found without reading the whole ledger.
+    in_flight: sled::Tree,
+}
+
+/// Current Unix timestamp, in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Ledger {
+    /// Open the ledger at the given path, creating it if needed.
+    pub fn open(path: &str) -> Result<Self> {
+        let db = sled::open(path)?;
+        let proofs = db.open_tree("proofs")?;
+        let in_flight = db.open_tree("in_flight")?;
+
+        // Rebuild the index, it didn't exist in older ledgers.
+        in_flight.clear()?;
+        for item in proofs.iter() {
+            let (key, value) = item?;
+            let entry: LedgerEntry = serde_json::from_slice(&value)?;
+            if entry.is_in_flight() {
+                in_flight.insert(key, IN_FLIGHT_MARKER)?;
+            }
+        }
+
+        Ok(Self { proofs, in_flight })
+    }
+
+    /// Get the entry for a proof request.
+    pub fn get(&self, proof_id: &str) -> Result<Option<LedgerEntry>> {
+        self.proofs
+            .get(proof_id)?
+            .map(|value| serde_json::from_slice(&value).map_err(anyhow::Error::from))
+            .transpose()
+    }
+
+    fn put(&self, entry: &LedgerEntry) -> Result<()> {
+        let key = entry.proof_request.proof_id.as_str();
+        let value = serde_json::to_vec(entry)?;
+        (&self.proofs, &self.in_flight)
+            .transaction(|(proofs, in_flight)| {
+                proofs.insert(key, value.as_slice())?;
+                if entry.is_in_flight() {
+                    in_flight.insert(key, IN_FLIGHT_MARKER)?;
+                } else {
+                    in_flight.remove(key)?;
+                }
+                Ok::<_, sled::transaction::ConflictableTransactionError>(())
+            })
+            .map_err(|e| anyhow::anyhow!("Failed to update ledger: {:?}", e))?;
+        self.proofs.flush()?;
+
+        Ok(())
+    }
+
+    fn transition(
+        &self,
+        proof_id: &str,
+        status: LedgerStatus,
+        update: impl FnOnce(&mut LedgerEntry),
+    ) -> Result<()> {
+        let mut entry = self
+            .get(proof_id)?
+            .ok_or_else(|| anyhow::anyhow!("Proof with ID '{}' is not in the ledger", proof_id))?;
+        entry.status = status;
+        entry.transitions.push(StatusTransition { status, at: now() });
+        update(&mut entry);
+
+        self.put(&entry)
+    }
+
+    /// Record that a proof request was claimed on the network.
+    pub fn record_claim(&self, proof_request: &ProofRequest) -> Result<()> {
+        let claimed_at = now();
+        self.put(&LedgerEntry {
+            proof_request: proof_request.clone(),
+            claimed_at,
+            worker: None,
+            job_id: None,
+            status: LedgerStatus::Claimed,
+            transitions: vec![StatusTransition {
+                status: LedgerStatus::Claimed,
+                at: claimed_at,
+            }],
+            proving_seconds: None,
+            error: None,
+        })
+    }
+
+    /// Record that a worker accepted the proof request as a job.
+    pub fn record_dispatch(&self, proof_id: &str, worker: &str, job_id: &str) -> Result<()> {
+        self.transition(proof_id, LedgerStatus::Dispatched, |entry| {
+            entry.worker = Some(worker.to_string());
+            entry.job_id = Some(job_id.to_string());
+        })
+    }
+
+    /// Record that the proof was generated and fulfilled.
+    pub fn record_done(&self, proof_id: &str, proving_seconds: u64) -> Result<()> {
+        self.transition(proof_id, LedgerStatus::Done, |entry| {
+            entry.proving_seconds = Some(proving_seconds);
+        })
+    }
+
+    /// Record that the worker failed to generate the proof.
+    pub fn record_failed(&self, proof_id: &str, error: &anyhow::Error) -> Result<()> {
+        self.transition(proof_id, LedgerStatus::Failed, |entry| {
+            entry.error = Some(format!("{:#}", error));
+        })
+    }
+
+    /// Record that the master node unclaimed the proof request.
+    pub fn record_unclaimed(&self, proof_id: &str, error: &anyhow::Error) -> Result<()> {
+        self.transition(proof_id, LedgerStatus::Unclaimed, |entry| {
+            entry.error = Some(format!("{:#}", error));
+        })
+    }
+
+    /// All proof requests that were claimed or dispatched but have not finished.
+    pub fn in_flight(&self) -> Result<Vec<LedgerEntry>> {
+        let mut entries = Vec::new();
+        for item in self.in_flight.iter() {
+            let (key, _) = item?;
+            if let Some(value) = self.proofs.get(key)? {
+                entries.push(serde_json::from_slice(&value)?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// The number of proof requests in flight.
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Drop proof requests that finished longer than the retention period ago.
+    pub fn prune(&self) -> Result<()> {
+        let cutoff = now().saturating_sub(LEDGER_RETENTION_SECS);
+        let mut pruned = 0;
+        for item in self.proofs.iter() {
+            let (key, value) = item?;
+            let entry: LedgerEntry = serde_json::from_slice(&value)?;
+            let finished_at = entry.transitions.last().map_or(entry.claimed_at, |t| t.at);
+            if !entry.is_in_flight() && finished_at < cutoff {
+                self.proofs.remove(key)?;
+                pruned += 1;
+            }
+        }
+        if pruned > 0 {
+            self.proofs.flush()?;
+            info!("Pruned {} finished proof(s) from the ledger.", pruned);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sp1_sdk::proto::network::ProofMode;
+
+    fn proof_request(proof_id: &str) -> ProofRequest {
+        ProofRequest {
+            proof_id: proof_id.to_string(),
+            mode: ProofMode::Compressed,
+            program_artifact_id: "program".to_string(),
+            stdin_artifact_id: "stdin".to_string(),
+            proof_artifact_id: "proof".to_string(),
+        }
+    }
+
+    fn in_flight_ids(ledger: &Ledger) -> Vec<String> {
+        let mut ids: Vec<_> = ledger
+            .in_flight()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.proof_request.proof_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn in_flight_follows_status_transitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = Ledger::open(dir.path().to_str().unwrap()).unwrap();
+        for proof_id in ["claimed", "dispatched", "done", "failed", "unclaimed"] {
+            ledger.record_claim(&proof_request(proof_id)).unwrap();
+        }
+        let error = anyhow::anyhow!("error");
+        ledger
+            .record_dispatch("dispatched", "http://worker", "job")
+            .unwrap();
+        ledger
+            .record_dispatch("done", "http://worker", "job")
+            .unwrap();
+        ledger.record_done("done", 10).unwrap();
+        ledger.record_failed("failed", &error).unwrap();
+        ledger.record_unclaimed("unclaimed", &error).unwrap();
+
+        assert_eq!(in_flight_ids(&ledger), ["claimed", "dispatched"]);
+        assert_eq!(ledger.in_flight_count(), 2);
+
+        let entry = ledger.get("done").unwrap().unwrap();
+        assert_eq!(entry.status, LedgerStatus::Done);
+        assert_eq!(entry.proving_seconds, Some(10));
+        let statuses: Vec<_> = entry.transitions.iter().map(|t| t.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                LedgerStatus::Claimed,
+                LedgerStatus::Dispatched,
+                LedgerStatus::Done
+            ]
+        );
+        assert!(ledger.record_done("unknown", 10).is_err());
+    }
+
+    #[test]
+    fn in_flight_proofs_are_found_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        {
+            let ledger = Ledger::open(path).unwrap();
+            ledger.record_claim(&proof_request("dispatched")).unwrap();
+            ledger.record_claim(&proof_request("done")).unwrap();
+            ledger
+                .record_dispatch("dispatched", "http://worker", "job")
+                .unwrap();
+            ledger.record_done("done", 10).unwrap();
+        }
+
+        // `reconcile` resumes the job from the entry's worker and job ID.
+        let ledger = Ledger::open(path).unwrap();
+        let in_flight = ledger.in_flight().unwrap();
+        assert_eq!(in_flight.len(), 1);
+        let entry = &in_flight[0];
+        assert_eq!(entry.proof_request.proof_id, "dispatched");
+        assert_eq!(entry.status, LedgerStatus::Dispatched);
+        assert_eq!(entry.worker.as_deref(), Some("http://worker"));
+        assert_eq!(entry.job_id.as_deref(), Some("job"));
+    }
+}
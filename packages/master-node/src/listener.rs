@@ -1,11 +1,33 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
-use log::info;
+use clap::ValueEnum;
+use log::{error, info, warn};
+use prover_common::artifact::ProofRequestStatus;
+use prover_common::jobs::JobStatus;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sp1_sdk::{
     network::client::NetworkClient,
-    proto::network::{ProofMode, ProofStatus},
+    proto::network::{ProofMode, ProofStatus, UnclaimReason},
 };
+use tracing::Instrument;
+
+use crate::cluster::{ClusterServer, CLUSTER_WORKER};
+use crate::config::config;
+use crate::jobs::{submit_job, wait_for_job};
+use crate::ledger::{Ledger, LedgerStatus};
+use crate::metrics::{
+    CLAIMS_ATTEMPTED, CLAIMS_SUCCEEDED, DISPATCH_FAILURES, IN_FLIGHT, PROOFS_FULFILLED, QUEUE_DEPTH,
+};
+use crate::telemetry::{proof_span, TraceContext};
+use crate::workers::{WorkerLease, WorkerPool};
+
+/// How often the master node checks on in-flight proofs while shutting down.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ProofRequest {
@@ -16,61 +38,454 @@ pub struct ProofRequest {
     pub proof_artifact_id: String,
 }
 
+/// How claimed proof requests are handed to workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DispatchMode {
+    /// Send each proof request to a worker node's `/prove` endpoint.
+    Workers,
+    /// Split each proof request into tasks of the cluster service's proving pipeline.
+    Cluster,
+}
+
+/// State shared by the listener and the proof requests it dispatched.
+pub struct Master {
+    pub workers: Arc<WorkerPool>,
+    pub ledger: Arc<Ledger>,
+    pub cluster: Arc<ClusterServer>,
+    pub dispatch_mode: DispatchMode,
+}
+
 /// Listener function to listen for proof requests.
 /// This function will be called every second to check for new proof requests.
-/// If a proof request is found, it will claim the proof and send the proof request to a worker API endpoint to generate the proof.
-pub async fn listener() -> Result<()> {
-    // Create a new HTTP client and network client.
-    let http_client = Client::new();
-    let network_client = NetworkClient::new(&std::env::var("SP1_PRIVATE_KEY").unwrap());
-    let worker_node_endpoint = std::env::var("WORKER_NODE_ENDPOINT")?;
+/// It claims as many proof requests as there is capacity for, and dispatches each one either to the least loaded worker with a free slot or to the cluster's proving pipeline.
+pub async fn listener(master: &Master) -> Result<()> {
+    // Create a new network client.
+    let network_client = NetworkClient::new(&config().sp1_private_key);
 
-    // Get proof requests with status ProofRequested.
+    IN_FLIGHT.set(master.ledger.in_flight_count() as i64);
+
+    // Only claim proof requests that can be started on right away.
+    let capacity = match master.dispatch_mode {
+        DispatchMode::Workers => {
+            let http_client = Client::new();
+            master.workers.refresh_capacity(&http_client).await;
+            master.workers.available()
+        }
+        DispatchMode::Cluster => {
+            settle_cluster_proofs(&network_client, master).await?;
+            master.cluster.available()?
+        }
+    };
+
+    // Get proof requests with status ProofRequested. The queue depth is reported even when
+    // there is no capacity to claim any of them.
     let proof_requests = network_client
         .get_proof_requests(ProofStatus::ProofRequested)
         .await?;
+    QUEUE_DEPTH.set(proof_requests.proofs.len() as i64);
+
+    if capacity == 0 {
+        info!("All workers are busy.");
+        return Ok(());
+    }
+
+    if proof_requests.proofs.is_empty() {
+        info!("No proof requests found.");
+        return Ok(());
+    }
+
+    // Leave proof requests of requesters out of cycles for this month to other provers. They are
+    // left out before the free slots are counted, so they never take the place of other requests.
+    let mut proofs = Vec::new();
+    for proof_request in proof_requests.proofs {
+        if let Some((used, limit)) = master
+            .cluster
+            .exhausted_cycle_budget(&proof_request.requester)?
+        {
+            info!(
+                "Skipping proof with ID '{}': requester 0x{} is out of cycles for this month ({} of {} used).",
+                proof_request.proof_id,
+                hex::encode(&proof_request.requester),
+                used,
+                limit
+            );
+            continue;
+        }
+        proofs.push(proof_request);
+    }
+
+    // Claim up to one proof request per free slot and dispatch each. Only claimed proof requests
+    // take a slot, so wrapped proof requests skipped for want of a worker with the circuits, or
+    // requests that failed to be claimed, leave it to the next ones.
+    let mut claimed = 0;
+    for proof_request in proofs {
+        if claimed == capacity {
+            break;
+        }
 
-    // If there are proof requests, claim the first one and send it to the worker API endpoint.
-    if !proof_requests.proofs.is_empty() {
-        let proof_request = proof_requests.proofs[0].clone();
-        let claim_proof_res = network_client.claim_proof(&proof_request.proof_id).await?;
+        let worker = match master.dispatch_mode {
+            DispatchMode::Workers => {
+                let needs_circuits = matches!(
+                    ProofMode::try_from(proof_request.mode),
+                    Ok(ProofMode::Plonk | ProofMode::Groth16)
+                );
+                match master.workers.acquire(needs_circuits) {
+                    Some(worker) => Some(worker),
+                    // Other proof requests may still fit on workers without the circuits.
+                    None if needs_circuits && master.workers.available() > 0 => {
+                        info!(
+                            "Skipping proof with ID '{}': no free worker has the PlonK and Groth16 circuits installed yet.",
+                            proof_request.proof_id
+                        );
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            DispatchMode::Cluster => None,
+        };
+
+        let (span, trace) = proof_span(&proof_request.proof_id);
+        CLAIMS_ATTEMPTED.inc();
+        let claim_proof_res = match network_client
+            .claim_proof(&proof_request.proof_id)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                warn!(
+                    "Failed to claim proof with ID '{}': {:?}",
+                    proof_request.proof_id, e
+                );
+                continue;
+            }
+        };
+        CLAIMS_SUCCEEDED.inc();
+        claimed += 1;
         info!(
             "Proof with ID '{}' has been successfully claimed.",
             proof_request.proof_id
         );
 
         // Create ProofRequest object.
+        let mode = match ProofMode::try_from(proof_request.mode) {
+            Ok(mode) => mode,
+            Err(_) => {
+                let e = anyhow::anyhow!("Invalid proof mode: {}", proof_request.mode);
+                error!("Proof with ID '{}': {}", proof_request.proof_id, e);
+                unclaim_proof(&network_client, &proof_request.proof_id, &e).await;
+                continue;
+            }
+        };
+        let requester = proof_request.requester;
         let proof_request = ProofRequest {
             proof_id: proof_request.proof_id,
-            mode: ProofMode::try_from(proof_request.mode)
-                .map_err(|_| anyhow::anyhow!("Invalid proof mode: {}", proof_request.mode))?,
+            mode,
             program_artifact_id: claim_proof_res.program_artifact_id,
             stdin_artifact_id: claim_proof_res.stdin_artifact_id,
             proof_artifact_id: claim_proof_res.proof_artifact_id,
         };
+        // A claimed proof that can't be tracked is unclaimed rather than left on the network.
+        let registered = master
+            .ledger
+            .record_claim(&proof_request)
+            .and_then(|_| master.cluster.register_proof(&proof_request, requester));
+        if let Err(e) = registered {
+            abandon(master, &proof_request.proof_id, e).await;
+            continue;
+        }
+
+        // Hand the proof request to the cluster's proving pipeline.
+        let Some(worker) = worker else {
+            let submitted = span.in_scope(|| {
+                let task_id = master
+                    .cluster
+                    .submit_proof(&proof_request, &trace.traceparent())?;
+                master
+                    .ledger
+                    .record_dispatch(&proof_request.proof_id, CLUSTER_WORKER, &task_id)
+            });
+            if let Err(e) = submitted {
+                abandon(master, &proof_request.proof_id, e).await;
+            }
+            continue;
+        };
 
-        let proof_request_json = serde_json::to_string(&proof_request)?;
+        // Dispatch in the background so the listener can keep other workers busy.
+        let (ledger, cluster) = (master.ledger.clone(), master.cluster.clone());
+        tokio::spawn(
+            async move {
+                let proof_id = proof_request.proof_id.clone();
+                let result = dispatch(worker, proof_request, &ledger, &trace).await;
+                finish(&ledger, &cluster, &proof_id, result).await;
+            }
+            .instrument(span),
+        );
+    }
+
+    Ok(())
+}
+
+/// Pick up proof requests that were in flight when the master node last stopped.
+///
+/// Jobs still known to their worker are followed to completion again. Proof requests that never
+/// reached a worker, or whose worker is gone, are unclaimed so they don't sit on the network.
+/// Proof requests handed to the cluster are left to the cluster service, which keeps its own state.
+pub async fn reconcile(master: &Master) -> Result<()> {
+    let (workers, ledger, cluster) = (&master.workers, &master.ledger, &master.cluster);
+    let in_flight = ledger.in_flight()?;
+    info!("Reconciling {} in-flight proof(s).", in_flight.len());
+
+    for entry in in_flight {
+        let proof_id = entry.proof_request.proof_id.clone();
+        if entry.worker.as_deref() == Some(CLUSTER_WORKER) {
+            continue;
+        }
+
+        let resumable = match (entry.status, &entry.worker, &entry.job_id) {
+            (LedgerStatus::Dispatched, Some(endpoint), Some(job_id)) => workers
+                .lease(endpoint)
+                .map(|worker| (worker, job_id.clone())),
+            _ => None,
+        };
+
+        match resumable {
+            Some((worker, job_id)) => {
+                info!(
+                    "Resuming proof with ID '{}' as job '{}' on '{}'.",
+                    proof_id,
+                    job_id,
+                    worker.endpoint()
+                );
+                let (ledger, cluster) = (ledger.clone(), cluster.clone());
+                let (span, _) = proof_span(&proof_id);
+                tokio::spawn(
+                    async move {
+                        let result = follow_job(&worker, &proof_id, &job_id).await;
+                        finish(&ledger, &cluster, &proof_id, result).await;
+                    }
+                    .instrument(span),
+                );
+            }
+            None => {
+                let e = anyhow::anyhow!("Proof was not running on any known worker after restart");
+                finish(ledger, cluster, &proof_id, Err(DispatchError::Worker(e))).await;
+            }
+        }
+    }
+
+    Ok(())
+}
 
-        info!("Sending proof request to worker API endpoint.");
+/// Wait for the proof requests in flight to finish before the master node stops, for at most
+/// `timeout`. Proof requests that never reached a worker or were handed to the cluster are
+/// unclaimed after that so they don't sit on the network until the master node is back. Jobs
+/// already running on a worker node are left in the ledger for `reconcile`.
+pub async fn drain(master: &Master, timeout: Duration) -> Result<()> {
+    let network_client = NetworkClient::new(&config().sp1_private_key);
+    let deadline = Instant::now() + timeout;
 
-        // Send the proof request to the worker API endpoint.
-        let response = http_client
-            .post(worker_node_endpoint + "/prove")
-            .header("Content-Type", "application/json")
-            .body(proof_request_json)
-            .send()
-            .await?;
+    loop {
+        // Proofs sent to worker nodes are finished by their dispatch tasks, proofs handed to the
+        // cluster have to be settled here.
+        if let Err(e) = settle_cluster_proofs(&network_client, master).await {
+            error!("Failed to settle cluster proofs: {:?}", e);
+        }
 
-        // Get the proving time from the response.
-        let proving_seconds = response.text().await?;
+        let in_flight = master.ledger.in_flight()?;
+        if in_flight.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            for entry in in_flight {
+                let proof_id = &entry.proof_request.proof_id;
+                let on_cluster = entry.worker.as_deref() == Some(CLUSTER_WORKER);
+                // Jobs running on a worker node outlive the master node and are picked up again
+                // by `reconcile` on the next start.
+                if entry.status != LedgerStatus::Claimed && !on_cluster {
+                    continue;
+                }
+                let e = anyhow::anyhow!("Master node shut down before the proof was finished");
+                abandon(master, proof_id, e).await;
+            }
+            return Ok(());
+        }
 
         info!(
-            "Proof with ID '{}' has been successfully generated in {} seconds.",
-            proof_request.proof_id, proving_seconds
+            "Waiting for {} in-flight proof(s) to finish.",
+            in_flight.len()
         );
-    } else {
-        info!("No proof requests found.");
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Fulfill the proof requests the cluster finished proving and unclaim the ones it failed to prove.
+async fn settle_cluster_proofs(network_client: &NetworkClient, master: &Master) -> Result<()> {
+    let (cluster, ledger) = (&master.cluster, &master.ledger);
+    cluster.expire_dropped_tasks()?;
+    cluster.prune()?;
+
+    for proof_id in cluster.fulfillable_proofs()? {
+        let proving_seconds = match network_client.fulfill_proof(&proof_id).await {
+            Ok(res) => res.proving_seconds,
+            Err(e) => {
+                error!("Failed to fulfill proof with ID '{}': {:?}", proof_id, e);
+                continue;
+            }
+        };
+        finish(ledger, cluster, &proof_id, Ok(proving_seconds)).await;
+    }
+
+    for proof_id in cluster.failed_proofs()? {
+        let e = anyhow::anyhow!("A task of the proving pipeline failed");
+        finish(ledger, cluster, &proof_id, Err(DispatchError::Worker(e))).await;
     }
 
     Ok(())
 }
+
+/// Why a dispatched proof request did not produce a proof.
+#[derive(Debug)]
+enum DispatchError {
+    /// The worker could not be reached, or stopped answering about the job.
+    Worker(anyhow::Error),
+    /// The worker ran the job and reported it as failed.
+    Job(anyhow::Error),
+}
+
+/// Send a claimed proof request to a worker and wait for its job to finish.
+async fn dispatch(
+    worker: WorkerLease,
+    proof_request: ProofRequest,
+    ledger: &Ledger,
+    trace: &TraceContext,
+) -> Result<u64, DispatchError> {
+    let http_client = Client::new();
+
+    info!(
+        "Sending proof with ID '{}' to worker '{}'.",
+        proof_request.proof_id,
+        worker.endpoint()
+    );
+
+    // Submit the proof request to the worker.
+    let job_id = submit_job(&http_client, worker.endpoint(), &proof_request, trace)
+        .await
+        .map_err(DispatchError::Worker)?;
+    if let Err(e) = ledger.record_dispatch(&proof_request.proof_id, worker.endpoint(), &job_id) {
+        error!(
+            "Failed to record dispatch of proof with ID '{}': {:?}",
+            proof_request.proof_id, e
+        );
+    }
+
+    follow_job(&worker, &proof_request.proof_id, &job_id).await
+}
+
+/// Poll a job on a worker until it finishes and return the proving time.
+async fn follow_job(
+    worker: &WorkerLease,
+    proof_id: &str,
+    job_id: &str,
+) -> Result<u64, DispatchError> {
+    let http_client = Client::new();
+    let job = wait_for_job(&http_client, worker.endpoint(), job_id)
+        .await
+        .map_err(DispatchError::Worker)?;
+
+    if job.status != JobStatus::Done {
+        return Err(DispatchError::Job(anyhow::anyhow!(
+            "Job '{}' failed: {}",
+            job.id,
+            job.error.unwrap_or_default()
+        )));
+    }
+
+    let proving_seconds = job.proving_seconds.unwrap_or_default();
+    let phases = job
+        .timings
+        .iter()
+        .map(|t| format!("{:?}={:.1}s", t.phase, t.seconds))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!(
+        "Proof with ID '{}' has been successfully generated in {} seconds for {} cycles ({}).",
+        proof_id,
+        proving_seconds,
+        job.cycles.unwrap_or_default(),
+        phases
+    );
+
+    Ok(proving_seconds)
+}
+
+/// Record the outcome of a dispatched proof request, unclaiming it if no worker will finish it.
+async fn finish(
+    ledger: &Ledger,
+    cluster: &ClusterServer,
+    proof_id: &str,
+    result: Result<u64, DispatchError>,
+) {
+    // The cluster service tracks every claimed proof, whichever way it was dispatched.
+    let status = match result {
+        Ok(_) => ProofRequestStatus::Fulfilled,
+        Err(_) => ProofRequestStatus::Unclaimed,
+    };
+    if let Err(e) = cluster.set_proof_status(proof_id, status) {
+        error!(
+            "Failed to update cluster status of proof with ID '{}': {:?}",
+            proof_id, e
+        );
+    }
+
+    let recorded = match result {
+        Ok(proving_seconds) => {
+            PROOFS_FULFILLED.inc();
+            ledger.record_done(proof_id, proving_seconds)
+        }
+        // The worker unclaims proofs it failed to generate itself.
+        Err(DispatchError::Job(e)) => {
+            DISPATCH_FAILURES.with_label_values(&["job"]).inc();
+            error!("Failed to generate proof with ID '{}': {:?}", proof_id, e);
+            ledger.record_failed(proof_id, &e)
+        }
+        // The worker never got the proof or stopped answering, so nobody else will.
+        Err(DispatchError::Worker(e)) => {
+            DISPATCH_FAILURES.with_label_values(&["worker"]).inc();
+            error!("Failed to dispatch proof with ID '{}': {:?}", proof_id, e);
+            let network_client = NetworkClient::new(&config().sp1_private_key);
+            unclaim_proof(&network_client, proof_id, &e).await;
+            ledger.record_unclaimed(proof_id, &e)
+        }
+    };
+
+    if let Err(e) = recorded {
+        error!(
+            "Failed to update ledger for proof with ID '{}': {:?}",
+            proof_id, e
+        );
+    }
+}
+
+/// Give up on a claimed proof request that no worker will finish.
+async fn abandon(master: &Master, proof_id: &str, error: anyhow::Error) {
+    let result = Err(DispatchError::Worker(error));
+    finish(&master.ledger, &master.cluster, proof_id, result).await;
+}
+
+/// Unclaim a proof request on the network so the requester isn't left waiting on a dead claim.
+async fn unclaim_proof(network_client: &NetworkClient, proof_id: &str, error: &anyhow::Error) {
+    match network_client
+        .unclaim_proof(
+            proof_id.to_string(),
+            UnclaimReason::Abandoned,
+            format!("{:#}", error),
+        )
+        .await
+    {
+        Ok(_) => info!("Proof with ID '{}' has been unclaimed.", proof_id),
+        Err(e) => error!("Failed to unclaim proof with ID '{}': {:?}", proof_id, e),
+    }
+}
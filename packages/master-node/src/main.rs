@@ -1,29 +1,124 @@
 //! Master node is responsible for listening to the proof requests and sending them to the worker node to generate the proof.
 extern crate dotenv;
 
+mod cluster;
+mod config;
+mod jobs;
+mod ledger;
 mod listener;
+mod metrics;
+mod telemetry;
+mod workers;
 
-use crate::listener::listener;
+use std::{future::Future, sync::Arc, time::Duration};
+
+use crate::cluster::{start_cluster_server, ClusterServer, ClusterStore};
+use crate::ledger::Ledger;
+use crate::listener::{drain, listener, reconcile, Master};
+use crate::workers::WorkerPool;
 
 use dotenv::dotenv;
-use log::info;
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often finished proof requests past their retention are dropped from the ledger.
+const LEDGER_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Resolve once the process receives SIGTERM or SIGINT. The handlers are installed right away so
+/// no signal is missed while the listener is busy.
+fn shutdown_signal() -> impl Future<Output = ()> {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    async move {
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    env_logger::init();
+    let config = match config::init() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    telemetry::init(&config.telemetry);
 
     info!("Starting master node server.");
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Serve the cluster task protocol in the background.
+    let cluster_store =
+        ClusterStore::open(&config.cluster_db_path).expect("Failed to open cluster store");
+    let cluster = Arc::new(ClusterServer::new(cluster_store));
+    let cluster_server = cluster.clone();
+    tokio::spawn(async move {
+        if let Err(e) = start_cluster_server(cluster_server).await {
+            error!("Cluster service stopped: {:?}", e);
+        }
+    });
+
+    let endpoints = config
+        .worker_node_endpoints
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let workers = Arc::new(WorkerPool::new(&endpoints));
+    info!("Dispatching to {} worker(s).", workers.workers().len());
+
+    let master = Master {
+        workers,
+        ledger: Arc::new(Ledger::open(&config.ledger_path).expect("Failed to open ledger")),
+        cluster,
+        dispatch_mode: config.dispatch_mode,
+    };
+
+    // Keep the ledger from growing forever.
+    let ledger = master.ledger.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LEDGER_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = ledger.prune() {
+                error!("Failed to prune ledger: {:?}", e);
+            }
+        }
+    });
+
+    // Pick up where the previous run left off before claiming anything new.
+    reconcile(&master)
+        .await
+        .expect("Failed to reconcile in-flight proofs");
 
     loop {
-        match listener().await {
+        match listener(&master).await {
             Ok(_) => (),
             Err(e) => {
                 println!("Error: {:?}", e);
             }
         }
 
-        // Wait for 1 second before fetching proof requests again.
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        // Wait for 1 second before fetching proof requests again, unless asked to stop.
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+    }
+
+    // Stop claiming proof requests and let the ones in flight finish.
+    info!(
+        "Shutting down, waiting up to {}s for in-flight proofs to finish...",
+        config.shutdown_timeout.as_secs()
+    );
+    if let Err(e) = drain(&master, config.shutdown_timeout).await {
+        error!("Failed to drain in-flight proofs: {:?}", e);
     }
+    info!("Master node stopped.");
+    telemetry::shutdown().await;
 }
@@ -0,0 +1,71 @@
+//! Prometheus metrics of the master node, served on `GET /metrics` next to the cluster service.
+
+use anyhow::Result;
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref CLAIMS_ATTEMPTED: IntCounter = register_int_counter!(
+        "master_claims_attempted_total",
+        "Proof requests the master node tried to claim."
+    )
+    .unwrap();
+    pub static ref CLAIMS_SUCCEEDED: IntCounter = register_int_counter!(
+        "master_claims_succeeded_total",
+        "Proof requests the master node claimed."
+    )
+    .unwrap();
+    pub static ref DISPATCH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "master_dispatch_failures_total",
+        "Claimed proof requests that did not produce a proof, by reason.",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref PROOFS_FULFILLED: IntCounter = register_int_counter!(
+        "master_proofs_fulfilled_total",
+        "Claimed proof requests that were fulfilled."
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "master_queue_depth",
+        "Proof requests waiting to be claimed on the network, as of the last poll."
+    )
+    .unwrap();
+    pub static ref IN_FLIGHT: IntGauge = register_int_gauge!(
+        "master_in_flight_proofs",
+        "Claimed proof requests that have not finished, as of the last poll."
+    )
+    .unwrap();
+}
+
+/// Render all metrics in the Prometheus text format.
+fn render() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Prometheus metrics endpoint.
+pub async fn metrics_handler() -> impl IntoResponse {
+    match render() {
+        Ok(metrics) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            metrics,
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
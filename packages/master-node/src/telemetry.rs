@@ -0,0 +1,62 @@
+//! The trace context propagated to worker nodes. Logging and trace export are set up by the
+//! shared [`prover_common::telemetry`].
+
+use opentelemetry::trace::TraceContextExt;
+pub use prover_common::telemetry::{init, shutdown};
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// A W3C trace context identifying the trace of a single proof request.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    /// 32 hex digits.
+    pub trace_id: String,
+    /// 16 hex digits.
+    pub span_id: String,
+    /// Whether the trace is exported.
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The context of a span as exported, or a new random one when spans are not exported.
+    fn of(span: &Span) -> Self {
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        if span_context.is_valid() {
+            return Self {
+                trace_id: span_context.trace_id().to_string(),
+                span_id: span_context.span_id().to_string(),
+                sampled: span_context.is_sampled(),
+            };
+        }
+
+        let span_id = uuid::Uuid::new_v4().simple().to_string();
+        Self {
+            trace_id: uuid::Uuid::new_v4().simple().to_string(),
+            span_id: span_id[..16].to_string(),
+            sampled: false,
+        }
+    }
+
+    /// The value of the `traceparent` header carrying this context.
+    pub fn traceparent(&self) -> String {
+        let flags = if self.sampled { "01" } else { "00" };
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, flags)
+    }
+}
+
+/// The root span of everything the master node does for a proof request, and the trace context
+/// that continues it on a worker node.
+pub fn proof_span(proof_id: &str) -> (Span, TraceContext) {
+    let span = info_span!(
+        "proof",
+        proof_id = proof_id,
+        trace_id = field::Empty,
+        span_id = field::Empty
+    );
+    let trace = TraceContext::of(&span);
+    span.record("trace_id", trace.trace_id.as_str());
+    span.record("span_id", trace.span_id.as_str());
+
+    (span, trace)
+}
@@ -0,0 +1,237 @@
+//! Pool of worker nodes that proof requests are dispatched to.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+use log::{debug, warn};
+use prover_common::capacity::{Capacity, CircuitState};
+use reqwest::Client;
+
+/// A worker node, its free proving slots and the number of proof requests currently dispatched
+/// to it.
+#[derive(Debug)]
+pub struct Worker {
+    pub endpoint: String,
+    available: AtomicUsize,
+    in_flight: AtomicUsize,
+    circuits_ready: AtomicBool,
+}
+
+impl Worker {
+    fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            available: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+            circuits_ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Number of free proving slots as of the last capacity refresh, minus the ones reserved since.
+    pub fn available(&self) -> usize {
+        self.available.load(Ordering::SeqCst)
+    }
+
+    /// Number of proof requests currently dispatched to this worker.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Whether the worker can generate PlonK and Groth16 proofs, as of the last capacity refresh.
+    pub fn circuits_ready(&self) -> bool {
+        self.circuits_ready.load(Ordering::SeqCst)
+    }
+
+    /// Query the worker for its capacity.
+    async fn fetch_capacity(&self, http_client: &Client) -> Result<Capacity> {
+        let capacity = http_client
+            .get(format!("{}/capacity", self.endpoint))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Capacity>()
+            .await?;
+
+        Ok(capacity)
+    }
+}
+
+/// A worker reserved for a single proof request. The reservation is released when dropped.
+#[derive(Debug)]
+pub struct WorkerLease {
+    worker: Arc<Worker>,
+}
+
+impl WorkerLease {
+    pub fn endpoint(&self) -> &str {
+        &self.worker.endpoint
+    }
+}
+
+impl Drop for WorkerLease {
+    fn drop(&mut self) {
+        self.worker.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The set of worker nodes known to the master node.
+#[derive(Debug)]
+pub struct WorkerPool {
+    workers: Vec<Arc<Worker>>,
+    next: AtomicUsize,
+}
+
+impl WorkerPool {
+    /// Create a pool from a list of worker endpoints. The pool is empty when proofs are dispatched
+    /// to the cluster.
+    pub fn new(endpoints: &[&str]) -> Self {
+        Self {
+            workers: endpoints.iter().map(|e| Arc::new(Worker::new(e))).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn workers(&self) -> &[Arc<Worker>] {
+        &self.workers
+    }
+
+    /// Ask every worker for its capacity. Unreachable workers are treated as having no free slots.
+    pub async fn refresh_capacity(&self, http_client: &Client) {
+        let capacities = futures::future::join_all(
+            self.workers
+                .iter()
+                .map(|worker| worker.fetch_capacity(http_client)),
+        )
+        .await;
+
+        for (worker, capacity) in self.workers.iter().zip(capacities) {
+            let (available, circuits) = match capacity {
+                Ok(capacity) => {
+                    debug!(
+                        "Worker '{}' has {}/{} slots available, circuits {:?}.",
+                        worker.endpoint, capacity.available, capacity.slots, capacity.circuits
+                    );
+                    (capacity.available, capacity.circuits)
+                }
+                Err(e) => {
+                    warn!("Failed to fetch capacity of '{}': {:?}", worker.endpoint, e);
+                    (0, CircuitState::default())
+                }
+            };
+            worker.available.store(available, Ordering::SeqCst);
+            worker
+                .circuits_ready
+                .store(circuits == CircuitState::Ready, Ordering::SeqCst);
+        }
+    }
+
+    /// Total number of free proving slots across all workers.
+    pub fn available(&self) -> usize {
+        self.workers.iter().map(|w| w.available()).sum()
+    }
+
+    /// Reserve a specific worker regardless of its free slots, for a job it is already running.
+    pub fn lease(&self, endpoint: &str) -> Option<WorkerLease> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let worker = self
+            .workers
+            .iter()
+            .find(|w| w.endpoint == endpoint)?
+            .clone();
+        worker.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        Some(WorkerLease { worker })
+    }
+
+    /// Reserve a free slot on the least loaded worker, if any. Ties are broken round-robin so that
+    /// equally loaded workers take turns. Proofs that need the PlonK and Groth16 circuits only go
+    /// to workers that have them installed.
+    pub fn acquire(&self, needs_circuits: bool) -> Option<WorkerLease> {
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        let worker = (0..self.workers.len())
+            .map(|i| &self.workers[(start + i) % self.workers.len()])
+            .filter(|w| w.available() > 0 && (!needs_circuits || w.circuits_ready()))
+            .min_by_key(|w| w.in_flight())?
+            .clone();
+        worker.available.fetch_sub(1, Ordering::SeqCst);
+        worker.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        Some(WorkerLease { worker })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pool of workers with the given free slots and circuit readiness.
+    fn pool(workers: &[(&str, usize, bool)]) -> WorkerPool {
+        let endpoints: Vec<&str> = workers.iter().map(|&(endpoint, _, _)| endpoint).collect();
+        let pool = WorkerPool::new(&endpoints);
+        for (worker, &(_, available, circuits_ready)) in pool.workers().iter().zip(workers) {
+            worker.available.store(available, Ordering::SeqCst);
+            worker
+                .circuits_ready
+                .store(circuits_ready, Ordering::SeqCst);
+        }
+        pool
+    }
+
+    #[test]
+    fn acquire_reserves_free_slots() {
+        let pool = pool(&[("http://a", 2, true), ("http://b", 1, true)]);
+        assert_eq!(pool.available(), 3);
+
+        let leases: Vec<_> = (0..3).map(|_| pool.acquire(false).unwrap()).collect();
+        assert!(pool.acquire(false).is_none());
+        assert_eq!(pool.available(), 0);
+        let in_flight: Vec<_> = pool.workers().iter().map(|w| w.in_flight()).collect();
+        assert_eq!(in_flight, [2, 1]);
+
+        // Released slots only become available again with the next capacity refresh.
+        drop(leases);
+        assert!(pool.workers().iter().all(|w| w.in_flight() == 0));
+        assert!(pool.acquire(false).is_none());
+    }
+
+    #[test]
+    fn acquire_picks_the_least_loaded_worker() {
+        let pool = pool(&[("http://a", 4, true), ("http://b", 4, true)]);
+        let _running = pool.lease("http://a/").unwrap();
+
+        let lease = pool.acquire(false).unwrap();
+        assert_eq!(lease.endpoint(), "http://b");
+
+        // Equally loaded workers take turns.
+        let endpoints: Vec<_> = (0..4)
+            .map(|_| pool.acquire(false).unwrap().endpoint().to_string())
+            .collect();
+        assert_ne!(endpoints[0], endpoints[1]);
+        assert_ne!(endpoints[2], endpoints[3]);
+    }
+
+    #[test]
+    fn wrapped_proofs_need_circuits() {
+        let pool = pool(&[("http://a", 1, false), ("http://b", 1, true)]);
+
+        let lease = pool.acquire(true).unwrap();
+        assert_eq!(lease.endpoint(), "http://b");
+        assert!(pool.acquire(true).is_none());
+        assert_eq!(pool.acquire(false).unwrap().endpoint(), "http://a");
+    }
+
+    #[test]
+    fn lease_reserves_a_known_worker() {
+        let pool = pool(&[("http://a/", 0, false)]);
+
+        let lease = pool.lease("http://a").unwrap();
+        assert_eq!(lease.endpoint(), "http://a");
+        assert_eq!(pool.workers()[0].in_flight(), 1);
+        assert!(pool.lease("http://unknown").is_none());
+        drop(lease);
+        assert_eq!(pool.workers()[0].in_flight(), 0);
+    }
+}
@@ -0,0 +1,375 @@
+//! Worker-local cache of program ELFs and their proving and verifying keys.
+//!
+//! Programs are keyed by the SHA-256 of their ELF, with a second index from program artifact ID
+//! to ELF hash so that a program seen before is neither downloaded nor set up again. Each entry
+//! is a directory holding the bincode serialized keys, the proving key including the ELF. Least
+//! recently used entries are evicted once the cache grows past its size cap.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use prover_common::artifact::Artifact;
+use reqwest_middleware::ClientWithMiddleware;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sp1_sdk::{SP1ProvingKey, SP1VerifyingKey};
+use tracing::info_span;
+
+use crate::config::config;
+use crate::statics::PROGRAM_CACHE;
+use crate::store::ArtifactExt;
+
+const INDEX_FILE: &str = "index.json";
+const PK_FILE: &str = "pk.bin";
+const VK_FILE: &str = "vk.bin";
+
+/// The keys produced by the setup of a program. The proving key also holds the ELF.
+pub struct CachedProgram {
+    pub pk: SP1ProvingKey,
+    pub vk: SP1VerifyingKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Size of the entry on disk, in bytes.
+    size: u64,
+    /// Unix timestamp of the last time the entry was used, in seconds.
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    /// ELF hash of every program artifact whose program is cached.
+    artifacts: HashMap<String, String>,
+    /// Cached programs, keyed by ELF hash.
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl CacheIndex {
+    /// Forget the least recently used programs until the cache fits in `max_bytes` and return
+    /// their ELF hashes.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.entries.values().map(|e| e.size).sum::<u64>() > max_bytes {
+            let Some(lru) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&lru);
+            self.artifacts.retain(|_, h| *h != lru);
+            evicted.push(lru);
+        }
+
+        evicted
+    }
+}
+
+/// On-disk cache of programs and their keys with an LRU size cap.
+pub struct ProgramCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Read a cached program from its entry directory.
+fn read_program(dir: &Path) -> Result<CachedProgram> {
+    let pk_file = std::io::BufReader::new(std::fs::File::open(dir.join(PK_FILE))?);
+    let pk = bincode::deserialize_from(pk_file)?;
+    let vk = bincode::deserialize(&std::fs::read(dir.join(VK_FILE))?)?;
+
+    Ok(CachedProgram { pk, vk })
+}
+
+/// Write a program to a new entry directory, returning the size of the entry.
+fn write_program(dir: &Path, program: &CachedProgram) -> Result<u64> {
+    // Write to a temporary directory of its own first so readers never see a partial entry and
+    // concurrent writers of the same program don't interfere.
+    let parent = dir
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Program cache entry {:?} has no parent", dir))?;
+    let tmp_dir = tempfile::tempdir_in(parent)?;
+
+    let pk = bincode::serialize(&program.pk)?;
+    let vk = bincode::serialize(&program.vk)?;
+    std::fs::write(tmp_dir.path().join(PK_FILE), &pk)?;
+    std::fs::write(tmp_dir.path().join(VK_FILE), &vk)?;
+    if let Err(e) = std::fs::rename(tmp_dir.path(), dir) {
+        // Another writer may have cached the same program in the meantime.
+        if !dir.join(VK_FILE).exists() {
+            return Err(e.into());
+        }
+    }
+
+    Ok((pk.len() + vk.len()) as u64)
+}
+
+/// Open the program cache in `program_cache_dir`.
+pub fn init_program_cache() -> Result<()> {
+    let cache = ProgramCache::open(
+        &config().program_cache_dir,
+        config().program_cache_max_bytes,
+    )?;
+    PROGRAM_CACHE
+        .set(cache)
+        .map_err(|_| anyhow::anyhow!("Program cache is already open"))
+}
+
+/// Get the program cache opened by [`init_program_cache`].
+pub fn program_cache() -> Result<&'static ProgramCache> {
+    PROGRAM_CACHE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("Program cache is not open"))
+}
+
+impl ProgramCache {
+    /// Open the cache in the given directory, creating it if needed. A `max_bytes` of 0 disables
+    /// the cache.
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create program cache directory {:?}", dir))?;
+        let index = match std::fs::read(dir.join(INDEX_FILE)) {
+            Ok(index) => serde_json::from_slice(&index).unwrap_or_else(|e| {
+                warn!("Discarding unreadable program cache index: {:?}", e);
+                CacheIndex::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => CacheIndex::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Get the program of a program artifact and its keys, downloading the program and running
+    /// `setup` only if it is not cached yet.
+    pub async fn get<F>(
+        &self,
+        artifact: &Artifact,
+        http_client: &ClientWithMiddleware,
+        setup: F,
+    ) -> Result<CachedProgram>
+    where
+        F: FnOnce(&[u8]) -> (SP1ProvingKey, SP1VerifyingKey) + Send + 'static,
+    {
+        if self.max_bytes == 0 {
+            let elf = artifact.download::<Vec<u8>>(http_client).await?;
+            return run_setup(elf, setup).await;
+        }
+
+        // Skip the download for program artifacts seen before.
+        let hash = self
+            .index
+            .lock()
+            .unwrap()
+            .artifacts
+            .get(&artifact.id)
+            .cloned();
+        if let Some(hash) = hash {
+            if let Some(program) = self.load(&hash).await {
+                return Ok(program);
+            }
+        }
+
+        let elf = artifact.download::<Vec<u8>>(http_client).await?;
+        let hash = hex::encode(Sha256::digest(&elf));
+        self.index
+            .lock()
+            .unwrap()
+            .artifacts
+            .insert(artifact.id.clone(), hash.clone());
+
+        // The same program may have been uploaded under another artifact ID.
+        if let Some(program) = self.load(&hash).await {
+            return Ok(program);
+        }
+
+        let program = run_setup(elf, setup).await?;
+        self.insert(&hash, program).await
+    }
+
+    /// Load a cached program and mark it as used. Unreadable entries are dropped.
+    async fn load(&self, hash: &str) -> Option<CachedProgram> {
+        if !self.index.lock().unwrap().entries.contains_key(hash) {
+            return None;
+        }
+
+        let dir = self.dir.join(hash);
+        let program = match tokio::task::spawn_blocking(move || read_program(&dir)).await {
+            Ok(Ok(program)) => program,
+            Ok(Err(e)) => {
+                warn!(
+                    "Dropping unreadable program cache entry '{}': {:?}",
+                    hash, e
+                );
+                self.remove(hash);
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to read program cache entry '{}': {:?}", hash, e);
+                return None;
+            }
+        };
+
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.entries.get_mut(hash) {
+            entry.last_used = now();
+        }
+        self.save(&index);
+        info!("Program '{}' loaded from the cache.", hash);
+
+        Some(program)
+    }
+
+    /// Add a program to the cache and evict the least recently used programs beyond the size
+    /// cap. Failing to write the program to disk is not an error, the program is returned either
+    /// way.
+    async fn insert(&self, hash: &str, program: CachedProgram) -> Result<CachedProgram> {
+        let dir = self.dir.join(hash);
+        let (program, written) = tokio::task::spawn_blocking(move || {
+            let written = write_program(&dir, &program);
+            (program, written)
+        })
+        .await?;
+        let size = match written {
+            Ok(size) => size,
+            Err(e) => {
+                warn!("Failed to cache program '{}': {:?}", hash, e);
+                return Ok(program);
+            }
+        };
+
+        let mut index = self.index.lock().unwrap();
+        index.entries.insert(
+            hash.to_string(),
+            CacheEntry {
+                size,
+                last_used: now(),
+            },
+        );
+        for lru in index.evict(self.max_bytes) {
+            info!("Evicting program '{}' from the cache.", lru);
+            if let Err(e) = std::fs::remove_dir_all(self.dir.join(&lru)) {
+                warn!("Failed to remove program cache entry '{}': {:?}", lru, e);
+            }
+        }
+        self.save(&index);
+
+        Ok(program)
+    }
+
+    /// Forget a cache entry and delete it from disk.
+    fn remove(&self, hash: &str) {
+        let mut index = self.index.lock().unwrap();
+        index.entries.remove(hash);
+        index.artifacts.retain(|_, h| h != hash);
+        self.save(&index);
+        let _ = std::fs::remove_dir_all(self.dir.join(hash));
+    }
+
+    /// Persist the index so the cache survives restarts.
+    fn save(&self, index: &CacheIndex) {
+        let result = serde_json::to_vec(index)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| {
+                let tmp_path = self.dir.join(format!("{}.tmp", INDEX_FILE));
+                std::fs::write(&tmp_path, data)?;
+                std::fs::rename(&tmp_path, self.dir.join(INDEX_FILE))?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!("Failed to save the program cache index: {:?}", e);
+        }
+    }
+}
+
+/// Run the setup of a program on a blocking thread.
+async fn run_setup<F>(elf: Vec<u8>, setup: F) -> Result<CachedProgram>
+where
+    F: FnOnce(&[u8]) -> (SP1ProvingKey, SP1VerifyingKey) + Send + 'static,
+{
+    let span = info_span!("setup");
+    let program = tokio::task::spawn_blocking(move || {
+        let _enter = span.enter();
+        let (pk, vk) = setup(&elf);
+        CachedProgram { pk, vk }
+    })
+    .await?;
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(entries: &[(&str, u64, u64)]) -> CacheIndex {
+        let mut index = CacheIndex::default();
+        for &(hash, size, last_used) in entries {
+            index
+                .artifacts
+                .insert(format!("artifact_{}", hash), hash.to_string());
+            index
+                .entries
+                .insert(hash.to_string(), CacheEntry { size, last_used });
+        }
+        index
+    }
+
+    #[test]
+    fn evicts_least_recently_used_programs() {
+        let mut index = index(&[("a", 4, 1), ("b", 4, 3), ("c", 4, 2)]);
+
+        assert!(index.evict(12).is_empty());
+        assert_eq!(index.evict(8), ["a"]);
+        assert_eq!(index.evict(4), ["c"]);
+        assert!(index.entries.contains_key("b"));
+        assert_eq!(
+            index.artifacts.values().collect::<Vec<_>>(),
+            [&"b".to_string()]
+        );
+    }
+
+    #[test]
+    fn evicts_programs_larger_than_the_cache() {
+        let mut index = index(&[("a", 4, 1), ("b", 16, 2)]);
+
+        let mut evicted = index.evict(8);
+        evicted.sort();
+        assert_eq!(evicted, ["a", "b"]);
+        assert!(index.entries.is_empty());
+        assert!(index.artifacts.is_empty());
+    }
+
+    #[test]
+    fn index_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ProgramCache::open(dir.path(), 8).unwrap();
+        cache.save(&index(&[("a", 4, 1)]));
+
+        let cache = ProgramCache::open(dir.path(), 8).unwrap();
+        let index = cache.index.lock().unwrap();
+        assert_eq!(index.entries["a"].size, 4);
+        assert_eq!(index.artifacts["artifact_a"], "a");
+    }
+}
@@ -0,0 +1,124 @@
+//! Readiness of the PlonK and Groth16 circuit artifacts, which wrapped proofs can't be generated
+//! without.
+//!
+//! The artifacts are downloaded in the background on startup. Until they are installed, the
+//! worker reports itself as not ready for wrapped proofs and holds back proofs that need them.
+//! The offline `prove` command installs them in the foreground instead, and fails if it can't.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{error, info};
+use prover_common::capacity::CircuitState;
+use tokio::sync::watch;
+
+/// How long to wait before retrying a failed download, doubled after every failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest wait between two download attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Tracks the circuit artifacts and lets proofs wait for them.
+pub struct CircuitArtifacts {
+    state: watch::Sender<CircuitState>,
+}
+
+impl Default for CircuitArtifacts {
+    fn default() -> Self {
+        Self {
+            state: watch::Sender::new(CircuitState::Downloading),
+        }
+    }
+}
+
+impl CircuitArtifacts {
+    pub fn state(&self) -> CircuitState {
+        *self.state.borrow()
+    }
+
+    /// Wait until the artifacts are installed.
+    pub async fn wait_ready(&self) {
+        let mut state = self.state.subscribe();
+        // The sender lives as long as `self`, so this only returns once the artifacts are ready.
+        let _ = state.wait_for(|state| *state == CircuitState::Ready).await;
+    }
+
+    /// Download and install the artifacts, retrying until they are installed.
+    pub async fn install(&self) {
+        let mut retry_delay = INITIAL_RETRY_DELAY;
+        while let Err(e) = self.try_install().await {
+            error!("{:#}, retrying in {}s.", e, retry_delay.as_secs());
+            tokio::time::sleep(retry_delay).await;
+            retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Download and install the artifacts once, failing if the download fails.
+    pub async fn try_install(&self) -> Result<()> {
+        self.state.send_replace(CircuitState::Downloading);
+        let start_time = Instant::now();
+        // The installer panics if the download fails.
+        let installed =
+            tokio::task::spawn_blocking(sp1_sdk::install::try_install_circuit_artifacts).await;
+        if let Err(e) = installed {
+            self.state.send_replace(CircuitState::Failed);
+
+            // A partial download would be taken for an installed one on the next attempt.
+            let dir = sp1_sdk::install::install_circuit_artifacts_dir();
+            if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    error!("Failed to remove circuit artifacts at {:?}: {:?}", dir, e);
+                }
+            }
+
+            return Err(anyhow::anyhow!(
+                "Failed to install circuit artifacts: {}",
+                e
+            ));
+        }
+
+        info!(
+            "Circuit artifacts ready after {:.1} min.",
+            start_time.elapsed().as_secs_f64() / 60.0
+        );
+        self.state.send_replace(CircuitState::Ready);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Whether `wait_ready` returns within a short time.
+    async fn is_ready(circuits: &CircuitArtifacts) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), circuits.wait_ready())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn proofs_wait_until_the_circuits_are_ready() {
+        let circuits = Arc::new(CircuitArtifacts::default());
+        assert_eq!(circuits.state(), CircuitState::Downloading);
+        assert!(!is_ready(&circuits).await);
+
+        let waiter = tokio::spawn({
+            let circuits = circuits.clone();
+            async move { circuits.wait_ready().await }
+        });
+        circuits.state.send_replace(CircuitState::Failed);
+        assert!(!is_ready(&circuits).await);
+        assert!(!waiter.is_finished());
+
+        circuits.state.send_replace(CircuitState::Ready);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(is_ready(&circuits).await);
+    }
+}
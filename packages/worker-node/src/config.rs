@@ -0,0 +1,538 @@
+//! Configuration of the worker node, loaded once at startup from command-line flags, environment
+//! variables and an optional TOML file, in that order of precedence.
+
+use std::{fs, path::PathBuf, sync::OnceLock, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use prover_common::artifact::WorkerType;
+use prover_common::telemetry::{LogFormat, OtelExporter, TelemetryConfig};
+use serde::Deserialize;
+use sp1_sdk::proto::network::ProofMode;
+
+use crate::tasks::PROVE_TASK_WEIGHT;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Where artifacts are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactStoreKind {
+    S3,
+    Local,
+}
+
+/// Compression of intermediate cluster artifacts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// Command line of the worker node.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Generates proofs for the master node.",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Settings of `serve`, which is run when no command is given.
+    #[command(flatten)]
+    settings: Settings,
+}
+
+/// What the worker node was started to do.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Generate the proofs the master node sends over HTTP, and run cluster tasks.
+    Serve(Settings),
+    /// Generate a single proof from local files, without the network or the artifact store.
+    Prove(ProveArgs),
+}
+
+/// Proof modes, as given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProveMode {
+    Core,
+    Compressed,
+    Plonk,
+    Groth16,
+}
+
+impl From<ProveMode> for ProofMode {
+    fn from(mode: ProveMode) -> Self {
+        match mode {
+            ProveMode::Core => ProofMode::Core,
+            ProveMode::Compressed => ProofMode::Compressed,
+            ProveMode::Plonk => ProofMode::Plonk,
+            ProveMode::Groth16 => ProofMode::Groth16,
+        }
+    }
+}
+
+/// Arguments of `prove`.
+#[derive(Debug, Args)]
+pub struct ProveArgs {
+    /// ELF of the program.
+    #[arg(long)]
+    pub elf: PathBuf,
+    /// bincode encoded `SP1Stdin`, as stored in stdin artifacts.
+    #[arg(long)]
+    pub stdin: PathBuf,
+    /// Kind of proof to generate.
+    #[arg(long, value_enum, default_value = "compressed")]
+    pub mode: ProveMode,
+    /// File the bincode encoded proof is written to, as stored in proof artifacts.
+    #[arg(long)]
+    pub out: PathBuf,
+    #[command(flatten)]
+    settings: Settings,
+}
+
+/// Settings of the worker node as given by a single source. Settings that are not given fall
+/// back to the next source, and then to their default.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// TOML file with settings, named like the long flags with underscores.
+    #[arg(long, env = "CONFIG_FILE")]
+    #[serde(skip)]
+    config: Option<String>,
+    /// secp256k1 private key the worker node fulfills and unclaims proofs with.
+    #[arg(long, env = "SP1_PRIVATE_KEY", hide_env_values = true)]
+    sp1_private_key: Option<String>,
+    /// Port of the HTTP server [default: 8080].
+    #[arg(long, env = "SERVER_PORT")]
+    server_port: Option<u16>,
+    /// Where artifacts are stored [default: s3].
+    #[arg(long, env = "ARTIFACT_STORE")]
+    artifact_store: Option<ArtifactStoreKind>,
+    /// Directory of the local artifact store [default: data/artifacts].
+    #[arg(long, env = "ARTIFACT_DIR")]
+    artifact_dir: Option<String>,
+    /// Compression of intermediate cluster artifacts [default: none].
+    #[arg(long, env = "ARTIFACT_COMPRESSION")]
+    artifact_compression: Option<Compression>,
+    /// zstd compression level [default: 3].
+    #[arg(long, env = "ARTIFACT_COMPRESSION_LEVEL")]
+    artifact_compression_level: Option<i32>,
+    /// Directory of the program and proving key cache [default: data/program-cache].
+    #[arg(long, env = "PROGRAM_CACHE_DIR")]
+    program_cache_dir: Option<String>,
+    /// Max size of the program cache in bytes, 0 to disable [default: 10737418240].
+    #[arg(long, env = "PROGRAM_CACHE_MAX_BYTES")]
+    program_cache_max_bytes: Option<u64>,
+    /// Number of S3 chunks transferred at the same time [default: 32].
+    #[arg(long, env = "S3_CONCURRENCY")]
+    s3_concurrency: Option<usize>,
+    /// S3 bucket name, required for the s3 artifact store.
+    #[arg(long, env = "S3_BUCKET")]
+    s3_bucket: Option<String>,
+    /// Endpoint of an S3-compatible service.
+    #[arg(long, env = "S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+    /// Address buckets by path instead of subdomain [default: false].
+    #[arg(long, env = "S3_FORCE_PATH_STYLE")]
+    s3_force_path_style: Option<bool>,
+    /// Accept any TLS certificate from the S3 endpoint [default: false].
+    #[arg(long, env = "S3_INSECURE_SKIP_TLS_VERIFY")]
+    s3_insecure_skip_tls_verify: Option<bool>,
+    /// Number of proofs generated at the same time [default: 1].
+    #[arg(long, env = "MAX_CONCURRENT_PROOFS")]
+    max_concurrent_proofs: Option<usize>,
+    /// Base URL of the master node cluster service, to run cluster tasks.
+    #[arg(long, env = "CLUSTER_RPC")]
+    cluster_rpc: Option<String>,
+    /// Base URL of the master node cluster service to charge the cycles of proofs to, without
+    /// running cluster tasks [default: CLUSTER_RPC].
+    #[arg(long, env = "CYCLES_RPC")]
+    cycles_rpc: Option<String>,
+    /// Reject programs that execute for more cycles than this.
+    #[arg(long, env = "MAX_CYCLES")]
+    max_cycles: Option<u64>,
+    /// Cluster tasks this worker runs: cpu, gpu, modal or all [default: all].
+    #[arg(long, env = "WORKER_TYPE")]
+    worker_type: Option<String>,
+    /// Max total weight of proving tasks run at the same time, controllers not included
+    /// [default: 4].
+    #[arg(long, env = "TASK_WEIGHT_BUDGET")]
+    task_weight_budget: Option<u32>,
+    /// Seconds to wait for running proofs on shutdown before unclaiming them [default: 300].
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Log output format [default: json].
+    #[arg(long, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Where spans are exported to [default: none].
+    #[arg(long, env = "OTEL_EXPORTER")]
+    otel_exporter: Option<OtelExporter>,
+    /// OTLP gRPC endpoint spans are sent to [default: http://localhost:4317].
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otel_exporter_otlp_endpoint: Option<String>,
+    /// File spans are appended to [default: traces.jsonl].
+    #[arg(long, env = "OTEL_EXPORTER_FILE")]
+    otel_exporter_file: Option<String>,
+    /// Share of proof requests whose traces are exported, between 0 and 1 [default: 1].
+    #[arg(long, env = "OTEL_SAMPLING_RATIO")]
+    otel_sampling_ratio: Option<f64>,
+    /// Service name in exported traces [default: sp1-prover-worker].
+    #[arg(long, env = "OTEL_SERVICE_NAME")]
+    otel_service_name: Option<String>,
+}
+
+impl Settings {
+    /// Fill the settings that are not given from another source.
+    fn or(self, other: Self) -> Self {
+        Self {
+            config: self.config.or(other.config),
+            sp1_private_key: self.sp1_private_key.or(other.sp1_private_key),
+            server_port: self.server_port.or(other.server_port),
+            artifact_store: self.artifact_store.or(other.artifact_store),
+            artifact_dir: self.artifact_dir.or(other.artifact_dir),
+            artifact_compression: self.artifact_compression.or(other.artifact_compression),
+            artifact_compression_level: self
+                .artifact_compression_level
+                .or(other.artifact_compression_level),
+            program_cache_dir: self.program_cache_dir.or(other.program_cache_dir),
+            program_cache_max_bytes: self
+                .program_cache_max_bytes
+                .or(other.program_cache_max_bytes),
+            s3_concurrency: self.s3_concurrency.or(other.s3_concurrency),
+            s3_bucket: self.s3_bucket.or(other.s3_bucket),
+            s3_endpoint: self.s3_endpoint.or(other.s3_endpoint),
+            s3_force_path_style: self.s3_force_path_style.or(other.s3_force_path_style),
+            s3_insecure_skip_tls_verify: self
+                .s3_insecure_skip_tls_verify
+                .or(other.s3_insecure_skip_tls_verify),
+            max_concurrent_proofs: self.max_concurrent_proofs.or(other.max_concurrent_proofs),
+            cluster_rpc: self.cluster_rpc.or(other.cluster_rpc),
+            cycles_rpc: self.cycles_rpc.or(other.cycles_rpc),
+            max_cycles: self.max_cycles.or(other.max_cycles),
+            worker_type: self.worker_type.or(other.worker_type),
+            task_weight_budget: self.task_weight_budget.or(other.task_weight_budget),
+            shutdown_timeout_secs: self.shutdown_timeout_secs.or(other.shutdown_timeout_secs),
+            log_format: self.log_format.or(other.log_format),
+            otel_exporter: self.otel_exporter.or(other.otel_exporter),
+            otel_exporter_otlp_endpoint: self
+                .otel_exporter_otlp_endpoint
+                .or(other.otel_exporter_otlp_endpoint),
+            otel_exporter_file: self.otel_exporter_file.or(other.otel_exporter_file),
+            otel_sampling_ratio: self.otel_sampling_ratio.or(other.otel_sampling_ratio),
+            otel_service_name: self.otel_service_name.or(other.otel_service_name),
+        }
+    }
+}
+
+/// Validated configuration of the worker node.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub sp1_private_key: String,
+    pub server_port: u16,
+    pub artifact_store: ArtifactStoreKind,
+    pub artifact_dir: String,
+    /// zstd compression level of intermediate cluster artifacts, if they are compressed.
+    pub artifact_compression_level: Option<i32>,
+    pub program_cache_dir: String,
+    pub program_cache_max_bytes: u64,
+    pub s3_concurrency: usize,
+    /// Set when the artifact store is S3.
+    pub s3_bucket: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_force_path_style: bool,
+    pub s3_insecure_skip_tls_verify: bool,
+    pub max_concurrent_proofs: usize,
+    pub cluster_rpc: Option<String>,
+    /// Where the cycles of executed proofs are charged to their requester, if anywhere.
+    pub cycles_rpc: Option<String>,
+    pub max_cycles: Option<u64>,
+    pub worker_type: WorkerType,
+    pub task_weight_budget: u32,
+    pub shutdown_timeout: Duration,
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Load the configuration from the given command-line settings, the environment and the TOML
+    /// file given by `--config` or `CONFIG_FILE`. Offline, the settings needed to reach the
+    /// network, the artifact store and the cluster are neither required nor used.
+    fn load(args: Settings, offline: bool) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Failed to parse config file {}", path))?
+            }
+            None => Settings::default(),
+        };
+
+        Self::resolve(args.or(file), offline)
+    }
+
+    /// Apply the defaults and check the settings, reporting every invalid one at once.
+    fn resolve(mut settings: Settings, offline: bool) -> Result<Self> {
+        let mut errors = Vec::new();
+
+        if offline {
+            settings.cluster_rpc = None;
+            settings.cycles_rpc = None;
+        }
+
+        let sp1_private_key = settings.sp1_private_key.unwrap_or_default();
+        let key = sp1_private_key.trim_start_matches("0x");
+        if key.is_empty() {
+            if !offline {
+                errors.push("SP1_PRIVATE_KEY must be set".to_string());
+            }
+        } else if hex::decode(key).map_or(true, |key| key.len() != 32) {
+            errors.push("SP1_PRIVATE_KEY must be 32 hex encoded bytes".to_string());
+        }
+
+        let artifact_store = settings.artifact_store.unwrap_or(ArtifactStoreKind::S3);
+        let s3_bucket = settings.s3_bucket.unwrap_or_default();
+        if artifact_store == ArtifactStoreKind::S3 && s3_bucket.is_empty() && !offline {
+            errors.push("S3_BUCKET must be set to use the S3 artifact store".to_string());
+        }
+
+        let artifact_compression_level = match settings.artifact_compression {
+            None | Some(Compression::None) => None,
+            Some(Compression::Zstd) => Some(settings.artifact_compression_level.unwrap_or(3)),
+        };
+        if let Some(level) = artifact_compression_level {
+            if !zstd::compression_level_range().contains(&level) {
+                errors.push(format!(
+                    "ARTIFACT_COMPRESSION_LEVEL must be within {:?}",
+                    zstd::compression_level_range()
+                ));
+            }
+        }
+
+        let s3_concurrency = settings.s3_concurrency.unwrap_or(32);
+        if s3_concurrency == 0 {
+            errors.push("S3_CONCURRENCY must be at least 1".to_string());
+        }
+
+        let max_concurrent_proofs = settings.max_concurrent_proofs.unwrap_or(1);
+        if max_concurrent_proofs == 0 {
+            errors.push("MAX_CONCURRENT_PROOFS must be at least 1".to_string());
+        }
+
+        if let Some(cluster_rpc) = &settings.cluster_rpc {
+            if let Err(e) = url::Url::parse(cluster_rpc) {
+                errors.push(format!("Invalid CLUSTER_RPC {}: {}", cluster_rpc, e));
+            }
+        }
+
+        let cycles_rpc = settings.cycles_rpc.or_else(|| settings.cluster_rpc.clone());
+        if let Some(cycles_rpc) = &cycles_rpc {
+            if let Err(e) = url::Url::parse(cycles_rpc) {
+                errors.push(format!("Invalid CYCLES_RPC {}: {}", cycles_rpc, e));
+            }
+        }
+
+        let worker_type = match settings.worker_type.as_deref().unwrap_or("all") {
+            "cpu" => WorkerType::Cpu,
+            "gpu" => WorkerType::Gpu,
+            "modal" => WorkerType::Modal,
+            "all" => WorkerType::All,
+            worker_type => {
+                errors.push(format!("Invalid WORKER_TYPE: {}", worker_type));
+                WorkerType::Unspecified
+            }
+        };
+
+        let otel_sampling_ratio = settings.otel_sampling_ratio.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&otel_sampling_ratio) {
+            errors.push("OTEL_SAMPLING_RATIO must be between 0 and 1".to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Invalid configuration:\n  - {}",
+                errors.join("\n  - ")
+            ));
+        }
+
+        Ok(Self {
+            sp1_private_key,
+            server_port: settings.server_port.unwrap_or(8080),
+            artifact_store,
+            artifact_dir: settings
+                .artifact_dir
+                .unwrap_or_else(|| "data/artifacts".to_string()),
+            artifact_compression_level,
+            program_cache_dir: settings
+                .program_cache_dir
+                .unwrap_or_else(|| "data/program-cache".to_string()),
+            program_cache_max_bytes: settings.program_cache_max_bytes.unwrap_or(10 << 30),
+            s3_concurrency,
+            s3_bucket,
+            s3_endpoint: settings.s3_endpoint,
+            s3_force_path_style: settings.s3_force_path_style.unwrap_or(false),
+            s3_insecure_skip_tls_verify: settings.s3_insecure_skip_tls_verify.unwrap_or(false),
+            max_concurrent_proofs,
+            cluster_rpc: settings.cluster_rpc,
+            cycles_rpc,
+            max_cycles: settings.max_cycles,
+            worker_type,
+            task_weight_budget: settings.task_weight_budget.unwrap_or(PROVE_TASK_WEIGHT),
+            shutdown_timeout: Duration::from_secs(settings.shutdown_timeout_secs.unwrap_or(300)),
+            telemetry: TelemetryConfig {
+                log_format: settings.log_format.unwrap_or(LogFormat::Json),
+                otel_exporter: settings.otel_exporter.unwrap_or(OtelExporter::None),
+                otel_exporter_otlp_endpoint: settings
+                    .otel_exporter_otlp_endpoint
+                    .unwrap_or_else(|| "http://localhost:4317".to_string()),
+                otel_exporter_file: settings
+                    .otel_exporter_file
+                    .unwrap_or_else(|| "traces.jsonl".to_string()),
+                otel_sampling_ratio,
+                otel_service_name: settings
+                    .otel_service_name
+                    .unwrap_or_else(|| "sp1-prover-worker".to_string()),
+            },
+        })
+    }
+}
+
+/// Parse the command line and load and validate the configuration of the command. Called once
+/// at startup, before anything reads the configuration.
+pub fn init() -> Result<Command> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve(cli.settings));
+    let config = match command {
+        Command::Serve(ref settings) => Config::load(settings.clone(), false)?,
+        Command::Prove(ref args) => Config::load(args.settings.clone(), true)?,
+    };
+    CONFIG.get_or_init(|| config);
+
+    Ok(command)
+}
+
+/// The configuration loaded at startup.
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("Configuration is not loaded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+
+    fn valid() -> Settings {
+        Settings {
+            sp1_private_key: Some(PRIVATE_KEY.to_string()),
+            s3_bucket: Some("artifacts".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resolve_applies_defaults() {
+        let config = Config::resolve(valid(), false).unwrap();
+        assert_eq!(config.server_port, 8080);
+        assert_eq!(config.artifact_store, ArtifactStoreKind::S3);
+        assert_eq!(config.artifact_compression_level, None);
+        assert_eq!(config.worker_type, WorkerType::All);
+        assert_eq!(config.task_weight_budget, PROVE_TASK_WEIGHT);
+        assert_eq!(config.cluster_rpc, None);
+        assert_eq!(config.cycles_rpc, None);
+        assert_eq!(config.telemetry.otel_service_name, "sp1-prover-worker");
+    }
+
+    #[test]
+    fn resolve_reports_every_error() {
+        let settings = Settings {
+            artifact_compression: Some(Compression::Zstd),
+            artifact_compression_level: Some(100),
+            max_concurrent_proofs: Some(0),
+            cluster_rpc: Some("not a url".to_string()),
+            worker_type: Some("tpu".to_string()),
+            otel_sampling_ratio: Some(-0.5),
+            ..Default::default()
+        };
+        let error = Config::resolve(settings, false).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid configuration:\n"));
+        for expected in [
+            "SP1_PRIVATE_KEY must be set",
+            "S3_BUCKET must be set to use the S3 artifact store",
+            "ARTIFACT_COMPRESSION_LEVEL must be within",
+            "MAX_CONCURRENT_PROOFS must be at least 1",
+            "Invalid CLUSTER_RPC not a url",
+            "Invalid CYCLES_RPC not a url",
+            "Invalid WORKER_TYPE: tpu",
+            "OTEL_SAMPLING_RATIO must be between 0 and 1",
+        ] {
+            assert!(
+                error.contains(expected),
+                "missing '{}' in {}",
+                expected,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn earlier_sources_take_precedence() {
+        let args = Settings {
+            server_port: Some(9000),
+            ..Default::default()
+        };
+        let file = Settings {
+            server_port: Some(9001),
+            artifact_dir: Some("artifacts".to_string()),
+            ..valid()
+        };
+        let config = Config::resolve(args.or(file), false).unwrap();
+        assert_eq!(config.server_port, 9000);
+        assert_eq!(config.artifact_dir, "artifacts");
+    }
+
+    #[test]
+    fn cycles_rpc_defaults_to_cluster_rpc() {
+        let settings = Settings {
+            cluster_rpc: Some("http://master:3000/twirp".to_string()),
+            ..valid()
+        };
+        let config = Config::resolve(settings, false).unwrap();
+        assert_eq!(
+            config.cycles_rpc.as_deref(),
+            Some("http://master:3000/twirp")
+        );
+
+        let settings = Settings {
+            cluster_rpc: Some("http://master:3000/twirp".to_string()),
+            cycles_rpc: Some("http://cycles:3000/twirp".to_string()),
+            ..valid()
+        };
+        let config = Config::resolve(settings, false).unwrap();
+        assert_eq!(
+            config.cycles_rpc.as_deref(),
+            Some("http://cycles:3000/twirp")
+        );
+    }
+
+    #[test]
+    fn offline_needs_no_network_settings() {
+        let settings = Settings {
+            cluster_rpc: Some("http://master:3000/twirp".to_string()),
+            ..Default::default()
+        };
+        let config = Config::resolve(settings, true).unwrap();
+        assert_eq!(config.cluster_rpc, None);
+        assert_eq!(config.cycles_rpc, None);
+
+        // Settings that are given are still checked.
+        let settings = Settings {
+            sp1_private_key: Some("0x1234".to_string()),
+            ..Default::default()
+        };
+        let error = Config::resolve(settings, true).unwrap_err().to_string();
+        assert!(error.contains("SP1_PRIVATE_KEY must be 32 hex encoded bytes"));
+    }
+}
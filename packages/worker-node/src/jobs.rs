@@ -0,0 +1,223 @@
+//! Asynchronous proof jobs tracked by the worker node.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use prover_common::jobs::{Job, JobStatus, PhaseTiming};
+
+/// How long finished jobs are kept around for status queries.
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Entry {
+    job: Job,
+    phase_started: Instant,
+    finished_at: Option<Instant>,
+}
+
+/// Registry of all jobs known to this worker.
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, Entry>>,
+}
+
+impl JobRegistry {
+    /// Register a new queued job for a proof request.
+    pub fn create(self: &Arc<Self>, proof_id: &str) -> JobHandle {
+        let id = uuid::Uuid::new_v4().to_string();
+        let job = Job {
+            id: id.clone(),
+            proof_id: proof_id.to_string(),
+            status: JobStatus::Queued,
+            error: None,
+            proving_seconds: None,
+            cycles: None,
+            timings: Vec::new(),
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, entry| {
+            !matches!(entry.finished_at, Some(t) if t.elapsed() >= FINISHED_JOB_RETENTION)
+        });
+        jobs.insert(
+            id.clone(),
+            Entry {
+                job,
+                phase_started: Instant::now(),
+                finished_at: None,
+            },
+        );
+
+        JobHandle {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    /// Get a snapshot of a job.
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).map(|entry| entry.job.clone())
+    }
+
+    /// Number of jobs that have not finished yet, including queued ones.
+    pub fn active(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| !entry.job.status.is_finished())
+            .count()
+    }
+
+    /// Fail every job that has not finished yet and return the proof IDs of the failed jobs.
+    pub fn fail_unfinished(&self, error: &anyhow::Error) -> Vec<String> {
+        let unfinished: Vec<(String, String)> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| !entry.job.status.is_finished())
+            .map(|entry| (entry.job.id.clone(), entry.job.proof_id.clone()))
+            .collect();
+
+        unfinished
+            .into_iter()
+            .map(|(id, proof_id)| {
+                self.transition(&id, JobStatus::Failed, |job| {
+                    job.error = Some(format!("{:#}", error));
+                });
+                proof_id
+            })
+            .collect()
+    }
+
+    /// Move a job to a new status, recording how long it spent in the previous one.
+    fn transition(&self, id: &str, status: JobStatus, update: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(entry) = jobs.get_mut(id) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let previous = entry.job.status;
+        entry.job.timings.push(PhaseTiming {
+            phase: previous,
+            seconds: (now - entry.phase_started).as_secs_f64(),
+        });
+        entry.job.status = status;
+        entry.phase_started = now;
+        if status.is_finished() {
+            entry.finished_at = Some(now);
+        }
+        update(&mut entry.job);
+    }
+}
+
+/// Handle used by a running job to report its progress.
+#[derive(Clone)]
+pub struct JobHandle {
+    registry: Arc<JobRegistry>,
+    id: String,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Enter the next phase of the job.
+    pub fn set_status(&self, status: JobStatus) {
+        self.registry.transition(&self.id, status, |_| {});
+    }
+
+    /// Record the cycle count of the program.
+    pub fn set_cycles(&self, cycles: u64) {
+        if let Some(entry) = self.registry.jobs.lock().unwrap().get_mut(&self.id) {
+            entry.job.cycles = Some(cycles);
+        }
+    }
+
+    /// Mark the job as done.
+    pub fn done(&self, proving_seconds: u64) {
+        self.registry.transition(&self.id, JobStatus::Done, |job| {
+            job.proving_seconds = Some(proving_seconds);
+        });
+    }
+
+    /// Mark the job as failed.
+    pub fn fail(&self, error: &anyhow::Error) {
+        self.registry.transition(&self.id, JobStatus::Failed, |job| {
+            job.error = Some(format!("{:#}", error));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(job: &Job) -> Vec<JobStatus> {
+        job.timings.iter().map(|timing| timing.phase).collect()
+    }
+
+    #[test]
+    fn jobs_record_their_phases() {
+        let registry = Arc::new(JobRegistry::default());
+        let handle = registry.create("proof");
+        let job = registry.get(handle.id()).unwrap();
+        assert_eq!(job.proof_id, "proof");
+        assert_eq!(job.status, JobStatus::Queued);
+        assert!(job.timings.is_empty());
+
+        handle.set_status(JobStatus::Executing);
+        handle.set_cycles(100);
+        handle.set_status(JobStatus::Proving);
+        handle.done(10);
+
+        let job = registry.get(handle.id()).unwrap();
+        assert_eq!(job.status, JobStatus::Done);
+        assert_eq!((job.cycles, job.proving_seconds), (Some(100), Some(10)));
+        assert_eq!(
+            statuses(&job),
+            [JobStatus::Queued, JobStatus::Executing, JobStatus::Proving]
+        );
+        assert_eq!(registry.active(), 0);
+    }
+
+    #[test]
+    fn failed_jobs_keep_their_error() {
+        let registry = Arc::new(JobRegistry::default());
+        let handle = registry.create("proof");
+        handle.set_status(JobStatus::Fetching);
+        handle.fail(&anyhow::anyhow!("download failed"));
+
+        let job = registry.get(handle.id()).unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("download failed"));
+        assert!(registry.get("unknown").is_none());
+    }
+
+    #[test]
+    fn fail_unfinished_fails_only_running_jobs() {
+        let registry = Arc::new(JobRegistry::default());
+        let queued = registry.create("queued");
+        let proving = registry.create("proving");
+        let done = registry.create("done");
+        proving.set_status(JobStatus::Proving);
+        done.done(10);
+        assert_eq!(registry.active(), 2);
+
+        let mut failed = registry.fail_unfinished(&anyhow::anyhow!("shutting down"));
+        failed.sort();
+        assert_eq!(failed, ["proving", "queued"]);
+        assert_eq!(registry.active(), 0);
+        for handle in [queued, proving] {
+            let job = registry.get(handle.id()).unwrap();
+            assert_eq!(job.status, JobStatus::Failed);
+            assert_eq!(job.error.as_deref(), Some("shutting down"));
+        }
+        assert_eq!(registry.get(done.id()).unwrap().status, JobStatus::Done);
+    }
+}
@@ -2,60 +2,155 @@
 
 extern crate dotenv;
 
-mod artifact;
+mod cache;
+mod circuits;
+mod config;
+mod jobs;
+mod metrics;
 mod prove;
 mod s3;
 mod server;
 mod statics;
+mod store;
+mod tasks;
+mod telemetry;
 
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    future::Future,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 use dotenv::dotenv;
+use sp1_prover::SP1Prover;
 use sp1_sdk::ProverClient;
-use tokio::signal;
+use tokio::signal::unix::{signal, SignalKind};
 
+use crate::cache::init_program_cache;
+use crate::config::{config, Command};
+use crate::jobs::JobRegistry;
+use crate::prove::{prove_local, unclaim_proof};
 use crate::server::start_server;
+use crate::statics::{CIRCUITS, DRAINING};
+use crate::store::init_artifact_store;
+use crate::tasks::TaskWorker;
+
+/// How often the worker checks whether its running proofs and tasks finished while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resolve once the process receives SIGTERM or SIGINT. The handlers are installed right away so
+/// no signal is missed before the future is awaited.
+fn shutdown_signal() -> impl Future<Output = ()> {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    async move {
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
+        }
+    }
+}
+
+/// Wait for the running proofs and tasks to finish, for at most `shutdown_timeout`. Proofs still
+/// running after that are unclaimed. Tasks still running are left to the cluster, which hands
+/// them to another worker once they are no longer bumped.
+async fn drain(jobs: &JobRegistry, task_worker: Option<&TaskWorker>) {
+    let deadline = Instant::now() + config().shutdown_timeout;
+    while jobs.active() > 0 || task_worker.map_or(0, |w| w.in_flight()) > 0 {
+        if Instant::now() >= deadline {
+            let error = anyhow::anyhow!("Worker shut down before the proof was finished");
+            for proof_id in jobs.fail_unfinished(&error) {
+                if let Err(e) = unclaim_proof(&proof_id, &error).await {
+                    log::error!("Failed to unclaim proof with ID '{}': {:?}", proof_id, e);
+                }
+            }
+            return;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    env_logger::init();
+    let command = match config::init() {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+    };
+    telemetry::init(&config().telemetry);
+
+    match command {
+        Command::Serve(_) => serve().await,
+        Command::Prove(args) => {
+            let prover = Arc::new(std::thread::spawn(ProverClient::new).join().unwrap());
+            if let Err(e) = prove_local(args, prover).await {
+                log::error!("Failed to generate proof: {:?}", e);
+                telemetry::shutdown().await;
+                std::process::exit(1);
+            }
+        }
+    }
+
+    telemetry::shutdown().await;
+}
+
+/// Serve proof requests and run cluster tasks until the worker is asked to stop.
+async fn serve() {
+    let config = config();
+    let shutdown = shutdown_signal();
+
+    // Open the program cache and the artifact store up front, so a misconfigured worker fails
+    // to start rather than failing every proof.
+    init_program_cache().expect("Failed to open program cache");
+    init_artifact_store()
+        .await
+        .expect("Failed to open artifact store");
 
     // Create ProverClient
     let prover = Arc::new(std::thread::spawn(ProverClient::new).join().unwrap());
 
-    // Download plonk artifacts in the background
-    let plonk_available = Arc::new(AtomicBool::new(false));
-    let plonk_available_clone = plonk_available.clone();
-    tokio::task::spawn_blocking(move || {
-        let start_time = std::time::Instant::now();
-        // TODO: detect if it finished correctly or not.
-        sp1_sdk::install::try_install_circuit_artifacts();
-        plonk_available_clone.store(true, std::sync::atomic::Ordering::Relaxed);
-        let elapsed = start_time.elapsed();
-        log::info!(
-            "plonk artifacts ready after {:.1} min",
-            elapsed.as_secs() / 60
+    // Download the PlonK and Groth16 circuit artifacts in the background
+    tokio::spawn(CIRCUITS.install());
+
+    // Run tasks of the split proving pipeline if the worker is part of a cluster.
+    let task_worker = config.cluster_rpc.as_deref().map(|cluster_rpc| {
+        let sp1_prover = Arc::new(std::thread::spawn(SP1Prover::new).join().unwrap());
+        let task_worker = Arc::new(
+            TaskWorker::new(cluster_rpc, sp1_prover, prover.clone())
+                .expect("Failed to create task worker"),
         );
+        tokio::spawn(task_worker.clone().run());
+        task_worker
     });
 
     // Start the server
-    let (server, addr) = start_server(prover.clone())
+    let jobs = Arc::new(JobRegistry::default());
+    let (server, addr) = start_server(prover.clone(), jobs.clone())
         .await
         .expect("Failed to start server");
 
     log::info!("Server running on {}. Press Ctrl-C to stop.", addr);
 
     // Spawn the server on a new task
+    let server_handle = server.handle();
     let server_task = tokio::spawn(server);
 
-    // Wait for a ctrl-c signal
-    signal::ctrl_c().await.expect("Failed to listen for ctrl-c");
+    // Wait for SIGTERM or SIGINT
+    shutdown.await;
 
-    log::info!("Shutting down server...");
+    // Stop taking on work, but keep serving job statuses to the master while draining.
+    log::info!(
+        "Shutting down, waiting up to {}s for running proofs to finish...",
+        config.shutdown_timeout.as_secs()
+    );
+    DRAINING.store(true, Ordering::SeqCst);
+    drain(&jobs, task_worker.as_deref()).await;
 
     // Stop the server
-    server_task.abort();
+    server_handle.stop(true).await;
 
     // Wait for the server to finish shutting down
     let _ = server_task.await;
@@ -0,0 +1,72 @@
+//! Prometheus metrics of the worker node, served on `GET /metrics`.
+
+use std::time::Instant;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounterVec, TextEncoder,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+lazy_static! {
+    pub static ref PROOFS: IntCounterVec = register_int_counter_vec!(
+        "worker_proofs_total",
+        "Proof jobs finished, by proof mode and outcome.",
+        &["mode", "outcome"]
+    )
+    .unwrap();
+    pub static ref PROVING_SECONDS: HistogramVec = register_histogram_vec!(
+        "worker_proving_duration_seconds",
+        "Time spent generating a proof, by proof mode.",
+        &["mode"],
+        exponential_buckets(10.0, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref TASKS: IntCounterVec = register_int_counter_vec!(
+        "worker_tasks_total",
+        "Cluster tasks finished, by task type and outcome.",
+        &["task_type", "outcome"]
+    )
+    .unwrap();
+    pub static ref ARTIFACT_BYTES: IntCounterVec = register_int_counter_vec!(
+        "worker_artifact_bytes_total",
+        "Bytes of artifacts transferred, by direction.",
+        &["direction"]
+    )
+    .unwrap();
+    pub static ref S3_CHUNK_SECONDS: HistogramVec = register_histogram_vec!(
+        "worker_s3_chunk_duration_seconds",
+        "Time taken by a single S3 chunk transfer attempt, by direction and outcome.",
+        &["direction", "outcome"],
+        exponential_buckets(0.05, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref SEMAPHORE_WAIT_SECONDS: HistogramVec = register_histogram_vec!(
+        "worker_semaphore_wait_seconds",
+        "Time spent waiting for a permit, by semaphore.",
+        &["semaphore"],
+        exponential_buckets(0.001, 4.0, 12).unwrap()
+    )
+    .unwrap();
+}
+
+/// Acquire a permit of a semaphore, recording how long it took under the given name.
+pub async fn acquire_timed<'a>(semaphore: &'a Semaphore, name: &str) -> SemaphorePermit<'a> {
+    let start = Instant::now();
+    let permit = semaphore.acquire().await.unwrap();
+    SEMAPHORE_WAIT_SECONDS
+        .with_label_values(&[name])
+        .observe(start.elapsed().as_secs_f64());
+
+    permit
+}
+
+/// Render all metrics in the Prometheus text format.
+pub fn render() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
@@ -1,15 +1,28 @@
 //! This module contains the logic for proving a proof request.
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
-use anyhow::Result;
-use log::info;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use prover_common::artifact::{Artifact, ClusterServiceClient, ModifyCpuCyclesRequest};
+use prover_common::jobs::JobStatus;
 use serde::{Deserialize, Serialize};
-use sp1_sdk::proto::network::ProofMode;
-use sp1_sdk::{network::client::NetworkClient, ProverClient, SP1Stdin};
+use sp1_core_executor::ExecutionReport;
+use sp1_sdk::proto::network::{ProofMode, UnclaimReason};
+use sp1_sdk::{
+    network::client::NetworkClient, ProverClient, SP1ProofWithPublicValues, SP1PublicValues,
+    SP1Stdin, SP1VerifyingKey,
+};
+use thiserror::Error;
+use tracing::{info_span, Instrument, Span};
+use twirp::client::Client as TwirpClient;
 
-use crate::artifact::Artifact;
-use crate::statics::HTTP_CLIENT_WITH_MIDDLEWARE;
+use crate::cache::{program_cache, CachedProgram};
+use crate::config::{config, ProveArgs};
+use crate::jobs::{JobHandle, JobRegistry};
+use crate::metrics::PROVING_SECONDS;
+use crate::statics::{CIRCUITS, HTTP_CLIENT_WITH_MIDDLEWARE};
+use crate::store::{ArtifactExt, ArtifactFile};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ProofRequest {
@@ -20,62 +33,98 @@ pub struct ProofRequest {
     pub proof_artifact_id: String,
 }
 
-/// Fetch the program and stdin artifacts from S3.
+/// The requester of a proof request ran out of CPU cycles for this month. Retrying the proof
+/// request won't help, so it is given up on right away.
+#[derive(Debug, Error)]
+#[error("requester is out of cycles for this month ({used} of {limit} used)")]
+pub struct CycleLimitExceeded {
+    pub used: i64,
+    pub limit: i64,
+}
+
+/// A generated proof does not verify against the verifying key of its program. This is a fault
+/// of the prover, so the proof is generated again rather than uploaded.
+#[derive(Debug, Error)]
+#[error("generated proof failed verification: {0}")]
+pub struct InvalidProof(String);
+
+/// How many times a proof is generated before giving up on proofs that fail verification.
+const PROVE_ATTEMPTS: usize = 3;
+
+/// Stands in for the proof ID of proofs generated from local files.
+const LOCAL_PROOF_ID: &str = "local";
+
+/// Fetch the program with its proving key and the stdin artifact. Programs that are not cached
+/// yet are set up with the given client. The stdin is kept in a temporary file and read from
+/// there whenever it is needed.
 async fn fetch_artifacts(
     program_artifact_id: String,
     stdin_artifact_id: String,
-) -> Result<(Vec<u8>, SP1Stdin)> {
+    client: Arc<ProverClient>,
+) -> Result<(CachedProgram, ArtifactFile)> {
     let http_client = HTTP_CLIENT_WITH_MIDDLEWARE.lock().unwrap().clone();
 
-    // Fetch the program artifact.
+    // Fetch the program artifact and its proving key.
     let program_artifact = Artifact::new(&program_artifact_id, "program");
-    let program: Vec<u8> = program_artifact
-        .download(&http_client)
-        .await
-        .map_err(anyhow::Error::from)?;
+    let program = program_cache()?
+        .get(&program_artifact, &http_client, move |elf| {
+            client.setup(elf)
+        })
+        .await?;
 
     // Fetch the stdin artifact.
     let stdin_artifact = Artifact::new(&stdin_artifact_id, "stdin");
-    let stdin = stdin_artifact
-        .download::<SP1Stdin>(&http_client)
-        .await
-        .map_err(anyhow::Error::from)?;
+    let stdin = stdin_artifact.download_to_file(&http_client).await?;
 
     Ok((program, stdin))
 }
 
-/// Generate the proof for the proof request.
-pub async fn generate_proof(proof_req: ProofRequest, client: Arc<ProverClient>) -> Result<u64> {
+/// Generate the proof for the proof request, reporting progress through the job handle.
+pub async fn generate_proof(
+    proof_req: ProofRequest,
+    client: Arc<ProverClient>,
+    job: JobHandle,
+) -> Result<u64> {
     info!(
-        "Generating proof for proof with ID '{}'",
-        proof_req.proof_id
+        "Generating proof for proof with ID '{}' in job '{}'",
+        proof_req.proof_id,
+        job.id()
     );
 
-    // Fetch the program and stdin artifacts.
-    let (program, stdin) =
-        fetch_artifacts(proof_req.program_artifact_id, proof_req.stdin_artifact_id).await?;
+    // Fetch the program and stdin artifacts, setting up the proving key if it isn't cached.
+    job.set_status(JobStatus::Fetching);
+    let (program, stdin) = fetch_artifacts(
+        proof_req.program_artifact_id,
+        proof_req.stdin_artifact_id,
+        client.clone(),
+    )
+    .instrument(info_span!("fetch"))
+    .await?;
 
-    // Setup the proving key.
-    let (pk, _) = client.setup(&program);
-
-    // Generate the proof.
-    let proof = tokio::task::spawn_blocking(move || match proof_req.mode {
-        ProofMode::Unspecified => Err(anyhow::anyhow!("Unspecified proof mode is not valid")),
-        ProofMode::Core => client.prove(&pk, stdin).run(),
-        ProofMode::Compressed => client.prove(&pk, stdin).compressed().run(),
-        ProofMode::Plonk => client.prove(&pk, stdin).plonk().run(),
-        ProofMode::Groth16 => client.prove(&pk, stdin).groth16().run(),
-    })
-    .await??;
+    // Execute the program, then generate and verify the proof.
+    let proof = prove_program(
+        &proof_req.proof_id,
+        proof_req.mode,
+        program,
+        &stdin,
+        client,
+        &job,
+    )
+    .await?;
 
     // Upload the proof artifact to S3.
+    job.set_status(JobStatus::Uploading);
     let http_client = HTTP_CLIENT_WITH_MIDDLEWARE.lock().unwrap().clone();
     let proof_artifact = Artifact::new(&proof_req.proof_artifact_id, "proof");
     proof_artifact.upload(&http_client, &proof).await?;
 
     // Fulfill the proof request.
-    let network_client = NetworkClient::new(&std::env::var("SP1_PRIVATE_KEY")?);
-    let proving_seconds = network_client.fulfill_proof(&proof_req.proof_id).await?;
+    job.set_status(JobStatus::Fulfilling);
+    let network_client = NetworkClient::new(&config().sp1_private_key);
+    let proving_seconds = network_client
+        .fulfill_proof(&proof_req.proof_id)
+        .instrument(info_span!("fulfill"))
+        .await?;
 
     info!(
         "Proof with ID '{}' has been successfully generated and fulfilled.",
@@ -84,3 +133,207 @@ pub async fn generate_proof(proof_req: ProofRequest, client: Arc<ProverClient>)
 
     Ok(proving_seconds.proving_seconds)
 }
+
+/// Execute the program, then generate the proof and check it before it goes anywhere, reporting
+/// progress through the job handle. The stdin is read from its file again for every run of the
+/// program rather than cloned.
+async fn prove_program(
+    proof_id: &str,
+    mode: ProofMode,
+    program: CachedProgram,
+    stdin: &ArtifactFile,
+    client: Arc<ProverClient>,
+    job: &JobHandle,
+) -> Result<SP1ProofWithPublicValues> {
+    // Execute the program first so that failing or oversized programs are rejected before
+    // spending any time proving them.
+    job.set_status(JobStatus::Executing);
+    let execute_client = client.clone();
+    let elf = program.pk.elf.clone();
+    let execute_stdin = stdin.read::<SP1Stdin>().await?;
+    let cycles = preflight(proof_id, move || {
+        let mut execute = execute_client.execute(&elf, execute_stdin);
+        if let Some(max_cycles) = config().max_cycles {
+            execute = execute.max_cycles(max_cycles);
+        }
+        execute.run()
+    })
+    .instrument(info_span!("execute"))
+    .await?;
+    job.set_cycles(cycles);
+
+    // Generate the proof and check it, proving again if it is invalid.
+    let program = Arc::new(program);
+    let mut attempt = 1;
+    let proof = loop {
+        job.set_status(JobStatus::Proving);
+        let prove_stdin = stdin.read::<SP1Stdin>().await?;
+        let (prove_client, prove_program) = (client.clone(), program.clone());
+        let proving_start = Instant::now();
+        let span = info_span!("prove", attempt);
+        let proof = tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            let pk = &prove_program.pk;
+            match mode {
+                ProofMode::Unspecified => {
+                    Err(anyhow::anyhow!("Unspecified proof mode is not valid"))
+                }
+                ProofMode::Core => prove_client.prove(pk, prove_stdin).run(),
+                ProofMode::Compressed => prove_client.prove(pk, prove_stdin).compressed().run(),
+                ProofMode::Plonk => prove_client.prove(pk, prove_stdin).plonk().run(),
+                ProofMode::Groth16 => prove_client.prove(pk, prove_stdin).groth16().run(),
+            }
+        })
+        .await??;
+        PROVING_SECONDS
+            .with_label_values(&[mode.as_str_name()])
+            .observe(proving_start.elapsed().as_secs_f64());
+
+        job.set_status(JobStatus::Verifying);
+        let verified = verify_proof(client.clone(), proof, program.vk.clone())
+            .instrument(info_span!("verify"))
+            .await;
+        match verified {
+            Ok(proof) => break proof,
+            Err(e) if e.is::<InvalidProof>() && attempt < PROVE_ATTEMPTS => {
+                warn!(
+                    "Proof with ID '{}' is invalid, proving again (attempt {} of {}): {:?}",
+                    proof_id,
+                    attempt + 1,
+                    PROVE_ATTEMPTS,
+                    e
+                );
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    Ok(proof)
+}
+
+/// Generate a proof from a local ELF and stdin file and write it to a file, going through the same
+/// execution, proving and verification as proof requests but without the network, the artifact
+/// store or the program cache.
+pub async fn prove_local(args: ProveArgs, client: Arc<ProverClient>) -> Result<()> {
+    let elf = tokio::fs::read(&args.elf)
+        .await
+        .with_context(|| format!("Failed to read ELF {}", args.elf.display()))?;
+    let stdin = ArtifactFile::open(&args.stdin)
+        .with_context(|| format!("Failed to read stdin {}", args.stdin.display()))?;
+    // Check that the stdin decodes before spending time on the setup.
+    stdin
+        .read::<SP1Stdin>()
+        .await
+        .with_context(|| format!("Failed to decode stdin {}", args.stdin.display()))?;
+
+    let setup_client = client.clone();
+    let span = info_span!("setup");
+    let (pk, vk) =
+        tokio::task::spawn_blocking(move || span.in_scope(|| setup_client.setup(&elf))).await?;
+
+    let mode = ProofMode::from(args.mode);
+    // Only wrapped proofs need the circuits. Without a retry loop, a failed download ends the
+    // command instead of hanging it.
+    if matches!(mode, ProofMode::Plonk | ProofMode::Groth16) {
+        CIRCUITS.try_install().await?;
+    }
+
+    let job = Arc::new(JobRegistry::default()).create(LOCAL_PROOF_ID);
+    let program = CachedProgram { pk, vk };
+    let proof = prove_program(LOCAL_PROOF_ID, mode, program, &stdin, client, &job).await?;
+
+    tokio::fs::write(&args.out, bincode::serialize(&proof)?)
+        .await
+        .with_context(|| format!("Failed to write proof {}", args.out.display()))?;
+    info!("Proof written to {}.", args.out.display());
+
+    Ok(())
+}
+
+/// Check a proof against the verifying key of its program, failing with [`InvalidProof`] if it
+/// does not verify. PlonK and Groth16 proofs are checked with the same circuit artifacts the
+/// network verifies them with.
+pub async fn verify_proof(
+    client: Arc<ProverClient>,
+    proof: SP1ProofWithPublicValues,
+    vk: SP1VerifyingKey,
+) -> Result<SP1ProofWithPublicValues> {
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _enter = span.enter();
+        client
+            .verify(&proof, &vk)
+            .map_err(|e| InvalidProof(e.to_string()))?;
+        Ok(proof)
+    })
+    .await?
+}
+
+/// Execute a program without proving it and return its cycle count.
+///
+/// Fails if the execution fails, including when it runs past `max_cycles`. When `cycles_rpc` is
+/// set, the cycles are charged to the requester through it, failing the proof request if the
+/// requester is out of cycles for this month.
+pub async fn preflight<F>(proof_id: &str, execute: F) -> Result<u64>
+where
+    F: FnOnce() -> Result<(SP1PublicValues, ExecutionReport)> + Send + 'static,
+{
+    let span = Span::current();
+    let (public_values, report) = tokio::task::spawn_blocking(move || span.in_scope(execute))
+        .await?
+        .map_err(|e| anyhow::anyhow!("Execution failed: {:#}", e))?;
+    let cycles = report.total_instruction_count();
+    info!(
+        "Proof with ID '{}' executed in {} cycles.",
+        proof_id, cycles
+    );
+    debug!(
+        "Proof with ID '{}' has public values 0x{}.",
+        proof_id,
+        hex::encode(public_values.as_slice())
+    );
+
+    if let Some(cycles_rpc) = &config().cycles_rpc {
+        let cluster = TwirpClient::from_base_url(url::Url::parse(cycles_rpc)?)?;
+        match cluster
+            .modify_cpu_cycles(ModifyCpuCyclesRequest {
+                cycles: cycles as i64,
+                proof_id: proof_id.to_string(),
+            })
+            .await
+        {
+            Ok(res) if res.exceeded_limit => {
+                return Err(CycleLimitExceeded {
+                    used: res.used,
+                    limit: res.limit,
+                }
+                .into());
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "Failed to report cycles of proof with ID '{}': {:?}",
+                proof_id, e
+            ),
+        }
+    }
+
+    Ok(cycles)
+}
+
+/// Unclaim a proof request on the network after proving failed, so the requester isn't left
+/// waiting on a dead claim and another prover can pick it up.
+pub async fn unclaim_proof(proof_id: &str, error: &anyhow::Error) -> Result<()> {
+    let network_client = NetworkClient::new(&config().sp1_private_key);
+    network_client
+        .unclaim_proof(
+            proof_id.to_string(),
+            UnclaimReason::Abandoned,
+            format!("{:#}", error),
+        )
+        .await?;
+
+    info!("Proof with ID '{}' has been unclaimed.", proof_id);
+
+    Ok(())
+}
@@ -1,99 +1,384 @@
 //! S3 operations for artifacts.
 
+use std::{
+    fs::File,
+    future::Future,
+    os::unix::fs::FileExt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
 use anyhow::Result;
+use async_trait::async_trait;
 use aws_config::{retry::RetryConfig, BehaviorVersion};
 use aws_sdk_s3::{
-    config::StalledStreamProtectionConfig,
+    config::{SharedHttpClient, StalledStreamProtectionConfig},
     primitives::{ByteStream, SdkBody},
     Client as S3Client,
 };
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
 use bytes::Bytes;
-use futures::future::join_all;
-use reqwest_middleware::ClientWithMiddleware as HttpClientWithMiddleware;
-use serde::{de::DeserializeOwned, Serialize};
-use tracing::{debug_span, instrument};
+use futures::future::try_join_all;
+use log::{error, warn};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ServerName,
+};
+use thiserror::Error;
 
 use crate::{
-    artifact::Artifact,
-    statics::{S3_BUCKET, S3_CLIENT, S3_CONCURRENCY, SEMAPHORE},
+    config::{config, Config},
+    metrics::{acquire_timed, S3_CHUNK_SECONDS},
+    statics::SEMAPHORE,
+    store::{ArtifactMetadata, ArtifactStore},
 };
 
 const CHUNK_SIZE: usize = 16 * 1024 * 1024;
 
-/// Get an S3 client.
-async fn get_s3_client() -> &'static S3Client {
-    S3_CLIENT
-        .get_or_init(|| async {
-            let mut base = aws_config::load_defaults(BehaviorVersion::latest())
-                .await
-                .to_builder();
-            base.set_retry_config(Some(RetryConfig::standard()));
-            base = base.stalled_stream_protection(StalledStreamProtectionConfig::disabled());
-            let config = base.build();
-            S3Client::new(&config)
+/// Artifacts stored in an S3 bucket under `artifacts/`.
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+}
+
+impl S3Store {
+    /// Create a store for the bucket in `s3_bucket`, using the AWS credentials from the
+    /// environment.
+    ///
+    /// S3-compatible services such as MinIO are supported through `s3_endpoint`,
+    /// `s3_force_path_style` and `s3_insecure_skip_tls_verify`.
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(endpoint) = &config.s3_endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        if config.s3_insecure_skip_tls_verify {
+            warn!("TLS certificate verification is disabled for S3.");
+            loader = loader.http_client(insecure_http_client());
+        }
+        let mut base = loader.load().await.to_builder();
+        base.set_retry_config(Some(RetryConfig::standard()));
+        base = base.stalled_stream_protection(StalledStreamProtectionConfig::disabled());
+        let s3_config = aws_sdk_s3::config::Builder::from(&base.build())
+            .force_path_style(config.s3_force_path_style)
+            .build();
+
+        Ok(Self {
+            client: S3Client::from_conf(s3_config),
+            bucket: config.s3_bucket.clone(),
         })
-        .await
+    }
 }
 
-/// Download a file from S3 using parallelization.
-async fn par_download_file<T: DeserializeOwned>(client: &S3Client, id: &str) -> Result<T> {
-    let key = format!("artifacts/{}", id);
-    let size = client
+/// An HTTP client that accepts any TLS certificate, for S3-compatible services with self-signed
+/// certificates.
+fn insecure_http_client() -> SharedHttpClient {
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+        .with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+
+    HyperClientBuilder::new().build(connector)
+}
+
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for S3Store {
+    async fn get(&self, id: &str) -> Result<(Vec<u8>, ArtifactMetadata)> {
+        Ok(par_download_file(&self.client, &self.bucket, id).await?)
+    }
+
+    async fn get_to_file(&self, id: &str, file: &File) -> Result<ArtifactMetadata> {
+        Ok(par_download_to_file(&self.client, &self.bucket, id, file).await?)
+    }
+
+    async fn put(&self, id: &str, data: Vec<u8>, metadata: &ArtifactMetadata) -> Result<()> {
+        Ok(par_upload_file(&self.client, &self.bucket, id, data, metadata).await?)
+    }
+}
+
+/// Errors from transferring an artifact to or from S3.
+#[derive(Debug, Error)]
+pub enum S3Error {
+    #[error("failed to get the size of '{key}'")]
+    Head { key: String, source: BoxError },
+    #[error("'{key}' has no content length")]
+    MissingContentLength { key: String },
+    #[error("failed to download bytes {start}-{end} of '{key}' after {attempts} attempts")]
+    DownloadChunk {
+        key: String,
+        start: i64,
+        end: i64,
+        attempts: u32,
+        source: BoxError,
+    },
+    #[error("failed to write bytes {start}.. of '{key}' to disk")]
+    WriteChunk {
+        key: String,
+        start: i64,
+        source: std::io::Error,
+    },
+    #[error("failed to start the upload of '{key}'")]
+    CreateUpload { key: String, source: BoxError },
+    #[error("S3 returned no upload ID for '{key}'")]
+    MissingUploadId { key: String },
+    #[error("failed to upload part {part} of '{key}' after {attempts} attempts")]
+    UploadPart {
+        key: String,
+        part: i32,
+        attempts: u32,
+        source: BoxError,
+    },
+    #[error("failed to complete the upload of '{key}'")]
+    CompleteUpload { key: String, source: BoxError },
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How many times a single chunk is tried before the whole transfer fails.
+const CHUNK_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a chunk, doubled on every further retry.
+const CHUNK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Run a chunk transfer, retrying it with exponential backoff. `direction` is either `download`
+/// or `upload` and labels the latency metric of every attempt.
+async fn retry_chunk<T, F, Fut>(direction: &str, what: &str, f: F) -> Result<T, BoxError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, BoxError>>,
+{
+    let mut delay = CHUNK_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let start = Instant::now();
+        let result = f().await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        S3_CHUNK_SECONDS
+            .with_label_values(&[direction, outcome])
+            .observe(start.elapsed().as_secs_f64());
+
+        match result {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < CHUNK_ATTEMPTS => {
+                warn!("Failed to transfer {} (attempt {}): {}", what, attempt, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Get the metadata and size of an object.
+async fn head_file(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+) -> Result<(ArtifactMetadata, i64), S3Error> {
+    let head = client
         .head_object()
-        .bucket((*S3_BUCKET).clone())
-        .key(key.clone())
+        .bucket(bucket)
+        .key(key)
         .send()
-        .await?
+        .await
+        .map_err(|e| S3Error::Head {
+            key: key.to_string(),
+            source: e.into(),
+        })?;
+    let metadata = head
+        .metadata()
+        .map(ArtifactMetadata::from_map)
+        .unwrap_or_default();
+    let size = head
         .content_length
-        .unwrap();
-    let mut buf = vec![0_u8; size as usize];
-    let starts = (0..size).step_by(CHUNK_SIZE);
-    let chunks = buf.chunks_mut(CHUNK_SIZE);
-    let threads = std::cmp::min(*S3_CONCURRENCY, chunks.len());
-    // Split into up to S3_CONCURRENCY threads. For each thread, acquire a permit and download chunks.
-    let mut chunk_inputs = starts.into_iter().zip(chunks).collect::<Vec<_>>();
-    let futures = chunk_inputs.chunks_mut(threads).map(|chunk_inputs| {
+        .ok_or_else(|| S3Error::MissingContentLength {
+            key: key.to_string(),
+        })?;
+
+    Ok((metadata, size))
+}
+
+/// Download an object in ranges using parallelization, handing every range to `write` together
+/// with its offset in the object.
+async fn par_download_ranges(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    size: i64,
+    write: &(dyn Fn(u64, &[u8]) -> std::io::Result<()> + Sync),
+) -> Result<(), S3Error> {
+    let starts = (0..size).step_by(CHUNK_SIZE).collect::<Vec<_>>();
+    let threads = std::cmp::max(std::cmp::min(config().s3_concurrency, starts.len()), 1);
+    // Split into up to `s3_concurrency` threads. For each thread, acquire a permit and download chunks.
+    let futures = starts.chunks(threads).map(|starts| {
         let client = client.clone();
-        let key = key.clone();
         async move {
-            let _permit = SEMAPHORE.acquire().await.unwrap();
-            for (start, chunk) in chunk_inputs {
-                let end = std::cmp::min(*start + chunk.len() as i64, size) - 1;
-                let res = client
-                    .get_object()
-                    .bucket((*S3_BUCKET).clone())
-                    .key(key.clone())
-                    .range(format!("bytes={}-{}", start, end))
-                    .send()
-                    .await?;
-                let body = res.body.collect().await.unwrap();
-                chunk.copy_from_slice(&body.to_vec());
+            let _permit = acquire_timed(&SEMAPHORE, "s3").await;
+            for &start in starts {
+                let end = std::cmp::min(start + CHUNK_SIZE as i64, size) - 1;
+                let len = (end - start + 1) as usize;
+                let range = format!("bytes={}-{}", start, end);
+                let (client, range) = (&client, &range);
+                let what = format!("{} of '{}'", range, key);
+                let body = retry_chunk("download", &what, || async move {
+                    let res = client
+                        .get_object()
+                        .bucket(bucket)
+                        .key(key)
+                        .range(range)
+                        .send()
+                        .await?;
+                    let body = res.body.collect().await?.into_bytes();
+                    if body.len() != len {
+                        return Err(
+                            format!("expected {} bytes but received {}", len, body.len()).into(),
+                        );
+                    }
+                    Ok::<_, BoxError>(body)
+                })
+                .await
+                .map_err(|source| S3Error::DownloadChunk {
+                    key: key.to_string(),
+                    start,
+                    end,
+                    attempts: CHUNK_ATTEMPTS,
+                    source,
+                })?;
+                write(start as u64, &body).map_err(|source| S3Error::WriteChunk {
+                    key: key.to_string(),
+                    start,
+                    source,
+                })?;
             }
-            Ok::<(), anyhow::Error>(())
+            Ok::<(), S3Error>(())
         }
     });
-    join_all(futures).await;
+    try_join_all(futures).await?;
 
-    let deserialized = debug_span!("deserialize").in_scope(|| bincode::deserialize(&buf))?;
-    Ok(deserialized)
+    Ok(())
 }
 
-/// Upload a file to S3 using parallelization.
-async fn par_upload_file<T: Serialize>(client: &S3Client, id: &str, item: T) -> Result<()> {
-    let data = debug_span!("serialize").in_scope(|| bincode::serialize(&item))?;
+/// Download a file and its metadata from S3 into memory.
+async fn par_download_file(
+    client: &S3Client,
+    bucket: &str,
+    id: &str,
+) -> Result<(Vec<u8>, ArtifactMetadata), S3Error> {
+    let key = format!("artifacts/{}", id);
+    let (metadata, size) = head_file(client, bucket, &key).await?;
+
+    let buf = Mutex::new(vec![0_u8; size as usize]);
+    par_download_ranges(client, bucket, &key, size, &|offset, data| {
+        let offset = offset as usize;
+        buf.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    })
+    .await?;
+
+    Ok((buf.into_inner().unwrap(), metadata))
+}
+
+/// Download a file from S3 into `file`, writing every range at its offset as it arrives.
+async fn par_download_to_file(
+    client: &S3Client,
+    bucket: &str,
+    id: &str,
+    file: &File,
+) -> Result<ArtifactMetadata, S3Error> {
+    let key = format!("artifacts/{}", id);
+    let (metadata, size) = head_file(client, bucket, &key).await?;
+
+    file.set_len(size as u64)
+        .map_err(|source| S3Error::WriteChunk {
+            key: key.clone(),
+            start: 0,
+            source,
+        })?;
+    par_download_ranges(client, bucket, &key, size, &|offset, data| {
+        file.write_all_at(data, offset)
+    })
+    .await?;
+
+    Ok(metadata)
+}
+
+/// Upload a file and its metadata to S3 using parallelization. The multipart upload is aborted if
+/// any part fails so that no orphaned parts are left in the bucket.
+async fn par_upload_file(
+    client: &S3Client,
+    bucket: &str,
+    id: &str,
+    data: Vec<u8>,
+    metadata: &ArtifactMetadata,
+) -> Result<(), S3Error> {
     let key = format!("artifacts/{}", id);
     let create_multipart_upload = client
         .create_multipart_upload()
-        .bucket((*S3_BUCKET).clone())
+        .bucket(bucket)
         .key(key.clone())
+        .set_metadata(Some(metadata.to_map()))
         .send()
-        .await?;
+        .await
+        .map_err(|e| S3Error::CreateUpload {
+            key: key.clone(),
+            source: e.into(),
+        })?;
 
-    let upload_id = create_multipart_upload.upload_id().unwrap();
+    let upload_id = create_multipart_upload
+        .upload_id()
+        .ok_or_else(|| S3Error::MissingUploadId { key: key.clone() })?;
 
+    let result = upload_parts(client, bucket, &key, upload_id, &data).await;
+    if result.is_err() {
+        if let Err(e) = client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key.clone())
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            error!("Failed to abort the upload of '{}': {:?}", key, e);
+        }
+    }
+
+    result
+}
+
+/// Upload the parts of a multipart upload and complete it.
+async fn upload_parts(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    data: &[u8],
+) -> Result<(), S3Error> {
     // Upload in parallel
-    let threads = std::cmp::min(*S3_CONCURRENCY, data.len());
-    // Split into up to S3_CONCURRENCY threads. For each thread, acquire a permit and upload chunks.
+    let threads = std::cmp::max(std::cmp::min(config().s3_concurrency, data.len()), 1);
+    // Split into up to `s3_concurrency` threads. For each thread, acquire a permit and upload chunks.
     let num_chunks = std::cmp::max((data.len() + CHUNK_SIZE - 1) / CHUNK_SIZE, 1);
     let mut parts = vec![None; num_chunks];
     let mut chunk_inputs = data
@@ -103,42 +388,57 @@ async fn par_upload_file<T: Serialize>(client: &S3Client, id: &str, item: T) ->
         .collect::<Vec<_>>();
     let futures = chunk_inputs.chunks_mut(threads).map(|chunk_inputs| {
         let client = client.clone();
-        let key = key.clone();
         async move {
-            let _permit = SEMAPHORE.acquire().await.unwrap();
+            let _permit = acquire_timed(&SEMAPHORE, "s3").await;
             for (i, (chunk, part_option)) in chunk_inputs {
+                let part_number = *i as i32 + 1;
                 let bytes = Bytes::from(chunk.to_vec());
-                let body = ByteStream::new(SdkBody::from(bytes));
-                let upload_part = client
-                    .upload_part()
-                    .bucket((*S3_BUCKET).clone())
-                    .key(key.clone())
-                    .upload_id(upload_id)
-                    .body(body)
-                    .part_number(*i as i32 + 1)
-                    .send();
-                let part = upload_part.await.unwrap();
+                let (client, bytes) = (&client, &bytes);
+                let e_tag = retry_chunk(
+                    "upload",
+                    &format!("part {} of '{}'", part_number, key),
+                    || async move {
+                        let part = client
+                            .upload_part()
+                            .bucket(bucket)
+                            .key(key)
+                            .upload_id(upload_id)
+                            .body(ByteStream::new(SdkBody::from(bytes.clone())))
+                            .part_number(part_number)
+                            .send()
+                            .await?;
+                        part.e_tag()
+                            .map(str::to_string)
+                            .ok_or_else(|| BoxError::from("S3 returned no ETag"))
+                    },
+                )
+                .await
+                .map_err(|source| S3Error::UploadPart {
+                    key: key.to_string(),
+                    part: part_number,
+                    attempts: CHUNK_ATTEMPTS,
+                    source,
+                })?;
 
                 part_option.replace(
                     aws_sdk_s3::types::CompletedPart::builder()
-                        .e_tag(part.e_tag().unwrap())
-                        .part_number(*i as i32 + 1)
+                        .e_tag(e_tag)
+                        .part_number(part_number)
                         .build(),
                 );
             }
+            Ok::<(), S3Error>(())
         }
     });
-    join_all(futures).await;
+    try_join_all(futures).await?;
 
-    let upload_parts = parts
-        .into_iter()
-        .map(|part_option| part_option.unwrap())
-        .collect::<Vec<_>>();
+    // Every part is set once all futures succeeded.
+    let upload_parts = parts.into_iter().flatten().collect::<Vec<_>>();
 
     client
         .complete_multipart_upload()
-        .bucket((*S3_BUCKET).clone())
-        .key(key.clone())
+        .bucket(bucket)
+        .key(key)
         .upload_id(upload_id)
         .multipart_upload(
             aws_sdk_s3::types::CompletedMultipartUpload::builder()
@@ -146,36 +446,52 @@ async fn par_upload_file<T: Serialize>(client: &S3Client, id: &str, item: T) ->
                 .build(),
         )
         .send()
-        .await?;
+        .await
+        .map_err(|e| S3Error::CompleteUpload {
+            key: key.to_string(),
+            source: e.into(),
+        })?;
 
     Ok(())
 }
 
-impl Artifact {
-    pub fn new(id: &str, label: &str) -> Self {
-        Self {
-            id: id.to_string(),
-            label: label.to_string(),
-            expiry: None,
-        }
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Run `retry_chunk` over a transfer that fails `failures` times before succeeding.
+    async fn transfer(failures: u32) -> (Result<u32, BoxError>, u32) {
+        let calls = AtomicU32::new(0);
+        let result = retry_chunk("download", "chunk", || async {
+            let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if call <= failures {
+                return Err(format!("attempt {} failed", call).into());
+            }
+            Ok(call)
+        })
+        .await;
+
+        (result, calls.load(Ordering::SeqCst))
     }
 
-    #[instrument(name = "download", level = "info", fields(label = self.label, id = self.id), skip_all)]
-    pub async fn download<T: DeserializeOwned>(
-        &self,
-        _client: &HttpClientWithMiddleware,
-    ) -> Result<T> {
-        let s3_client = get_s3_client().await;
-        par_download_file(s3_client, &self.id).await
+    #[tokio::test]
+    async fn chunks_are_retried_until_they_succeed() {
+        let (result, calls) = transfer(0).await;
+        assert_eq!((result.unwrap(), calls), (1, 1));
+
+        let (result, calls) = transfer(CHUNK_ATTEMPTS - 1).await;
+        assert_eq!((result.unwrap(), calls), (CHUNK_ATTEMPTS, CHUNK_ATTEMPTS));
     }
 
-    #[instrument(name = "upload", level = "info", fields(label = self.label, id = self.id), skip_all)]
-    pub async fn upload<T: Serialize>(
-        &self,
-        _client: &HttpClientWithMiddleware,
-        item: T,
-    ) -> Result<()> {
-        let s3_client = get_s3_client().await;
-        par_upload_file(s3_client, &self.id, item).await
+    #[tokio::test]
+    async fn chunks_fail_after_running_out_of_attempts() {
+        let (result, calls) = transfer(CHUNK_ATTEMPTS).await;
+        assert_eq!(calls, CHUNK_ATTEMPTS);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!("attempt {} failed", CHUNK_ATTEMPTS)
+        );
     }
 }
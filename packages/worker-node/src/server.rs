@@ -1,53 +1,154 @@
 //! Server module for the worker node.
 
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use log::info;
-use sp1_sdk::ProverClient;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use log::{error, info};
+use prover_common::capacity::Capacity;
+use serde::{Deserialize, Serialize};
+use sp1_sdk::{proto::network::ProofMode, ProverClient};
 use std::net::SocketAddr;
-use std::{env, sync::Arc};
+use std::sync::{atomic::Ordering, Arc};
+use tracing::Instrument;
 
-use crate::prove::{generate_proof, ProofRequest};
+use crate::config::config;
+use crate::jobs::JobRegistry;
+use crate::metrics::{self, acquire_timed, PROOFS};
+use crate::prove::{generate_proof, unclaim_proof, ProofRequest};
+use crate::statics::{CIRCUITS, DRAINING, PROOF_SEMAPHORE};
+use crate::telemetry::proof_span;
+
+/// Response to a proof submission.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmitProofResponse {
+    pub job_id: String,
+}
 
 /// Basic endpoint to check if the server is running
 async fn ping_api() -> impl Responder {
     HttpResponse::Ok().body("pong")
 }
 
-/// Proof generation endpoint.
+/// Proof generation endpoint. Queues the proof request and returns the job ID immediately.
 async fn generate_proof_api(
+    req: HttpRequest,
     program: web::Json<ProofRequest>,
     prover_client: web::Data<Arc<ProverClient>>,
+    jobs: web::Data<Arc<JobRegistry>>,
 ) -> impl Responder {
-    // Generate the proof and return the proving time.
-    let proving_seconds =
-        generate_proof(program.into_inner(), prover_client.get_ref().clone()).await;
+    if DRAINING.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json("worker is shutting down");
+    }
+
+    let proof_req = program.into_inner();
+    let job = jobs.create(&proof_req.proof_id);
+    let job_id = job.id().to_string();
+    let prover_client = prover_client.get_ref().clone();
+    let traceparent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|value| value.to_str().ok());
+    let span = proof_span(&proof_req.proof_id, &job_id, traceparent);
 
-    match proving_seconds {
-        Ok(proving_seconds) => HttpResponse::Ok().json(proving_seconds),
-        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    // Generate the proof in the background once a proving slot frees up and record the
+    // outcome on the job.
+    let generate = async move {
+        // Wrapped proofs stay queued until the circuit artifacts are installed.
+        if matches!(proof_req.mode, ProofMode::Plonk | ProofMode::Groth16) {
+            CIRCUITS.wait_ready().await;
+        }
+        let _permit = acquire_timed(&PROOF_SEMAPHORE, "proof").await;
+        let proof_id = proof_req.proof_id.clone();
+        let mode = proof_req.mode;
+        let result = if DRAINING.load(Ordering::SeqCst) {
+            Err(anyhow::anyhow!(
+                "Worker shut down before the proof was started"
+            ))
+        } else {
+            // Generate the proof in a task of its own so a panic fails the job too.
+            tokio::spawn(generate_proof(proof_req, prover_client, job.clone()).in_current_span())
+                .await
+                .unwrap_or_else(|e| Err(anyhow::anyhow!("Proof generation panicked: {}", e)))
+        };
+        let outcome = if result.is_ok() { "done" } else { "failed" };
+        PROOFS
+            .with_label_values(&[mode.as_str_name(), outcome])
+            .inc();
+        match result {
+            Ok(proving_seconds) => job.done(proving_seconds),
+            Err(e) => {
+                error!("Failed to generate proof with ID '{}': {:?}", proof_id, e);
+                job.fail(&e);
+                if let Err(e) = unclaim_proof(&proof_id, &e).await {
+                    error!("Failed to unclaim proof with ID '{}': {:?}", proof_id, e);
+                }
+            }
+        }
+    };
+    tokio::spawn(generate.instrument(span));
+
+    HttpResponse::Accepted().json(SubmitProofResponse { job_id })
+}
+
+/// Job status endpoint.
+async fn job_status_api(
+    path: web::Path<String>,
+    jobs: web::Data<Arc<JobRegistry>>,
+) -> impl Responder {
+    match jobs.get(&path.into_inner()) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().json("job not found"),
+    }
+}
+
+/// Capacity endpoint used by the master node to decide how many proofs to claim.
+async fn capacity_api(jobs: web::Data<Arc<JobRegistry>>) -> impl Responder {
+    let slots = config().max_concurrent_proofs;
+    let available = if DRAINING.load(Ordering::SeqCst) {
+        0
+    } else {
+        slots.saturating_sub(jobs.active())
+    };
+    HttpResponse::Ok().json(Capacity {
+        slots,
+        available,
+        circuits: CIRCUITS.state(),
+    })
+}
+
+/// Prometheus metrics endpoint.
+async fn metrics_api() -> impl Responder {
+    match metrics::render() {
+        Ok(metrics) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(metrics),
+        Err(e) => {
+            error!("Failed to render metrics: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
 }
 
 /// Start the worker node server.
 pub async fn start_server(
     prover_client: Arc<ProverClient>,
+    jobs: Arc<JobRegistry>,
 ) -> std::io::Result<(actix_web::dev::Server, SocketAddr)> {
     info!("Starting worker node server.");
 
-    let port = env::var("SERVER_PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse()
-        .expect("SERVER_PORT must be a number");
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let addr = SocketAddr::from(([0, 0, 0, 0], config().server_port));
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(prover_client.clone()))
+            .app_data(web::Data::new(jobs.clone()))
             .route("/ping", web::get().to(ping_api))
+            .route("/capacity", web::get().to(capacity_api))
             .route("/prove", web::post().to(generate_proof_api))
+            .route("/jobs/{id}", web::get().to(job_status_api))
+            .route("/metrics", web::get().to(metrics_api))
     })
     .bind(addr)?
+    // Shutdown signals are handled by `main`, which drains running proofs first.
+    .disable_signals()
     .run();
 
     Ok((server, addr))
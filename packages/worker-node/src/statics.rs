@@ -1,25 +1,28 @@
 //! Statics used throughout the worker node.
 
-use aws_sdk_s3::Client as S3Client;
 use lazy_static::lazy_static;
 use reqwest::Client;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::policies::ExponentialBackoff;
 use reqwest_retry::RetryTransientMiddleware;
-use std::{
-    env,
-    sync::{Arc, Mutex},
-};
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
 use tokio::sync::{OnceCell, Semaphore};
 
+use crate::cache::ProgramCache;
+use crate::circuits::CircuitArtifacts;
+use crate::config::config;
+use crate::store::ArtifactStore;
+
+/// Set once the worker is shutting down, after which it takes on no new proofs or tasks.
+pub static DRAINING: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
-    pub static ref S3_CLIENT: OnceCell<S3Client> = OnceCell::new();
-    pub static ref S3_CONCURRENCY: usize = env::var("S3_CONCURRENCY")
-        .unwrap_or("32".to_string())
-        .parse()
-        .unwrap();
-    pub static ref SEMAPHORE: Arc<Semaphore> = Arc::new(Semaphore::new(*S3_CONCURRENCY));
-    pub static ref S3_BUCKET: String = env::var("S3_BUCKET").expect("S3_BUCKET is not set");
+    pub static ref ARTIFACT_STORE: OnceCell<Arc<dyn ArtifactStore>> = OnceCell::new();
+    pub static ref PROGRAM_CACHE: OnceCell<ProgramCache> = OnceCell::new();
+    pub static ref SEMAPHORE: Arc<Semaphore> = Arc::new(Semaphore::new(config().s3_concurrency));
+    pub static ref PROOF_SEMAPHORE: Arc<Semaphore> =
+        Arc::new(Semaphore::new(config().max_concurrent_proofs));
+    pub static ref CIRCUITS: CircuitArtifacts = CircuitArtifacts::default();
     pub static ref HTTP_CLIENT_WITH_MIDDLEWARE: Mutex<ClientWithMiddleware> = Mutex::new({
         let reqwest_client = Client::new();
 
@@ -0,0 +1,523 @@
+//! Storage backends for artifacts.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use prover_common::artifact::Artifact;
+use reqwest_middleware::ClientWithMiddleware as HttpClientWithMiddleware;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug_span, instrument, Span};
+
+use crate::{
+    config::{config, ArtifactStoreKind},
+    metrics::ARTIFACT_BYTES,
+    s3::S3Store,
+    statics::ARTIFACT_STORE,
+};
+
+/// Metadata stored alongside the contents of an artifact.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ArtifactMetadata {
+    /// Hex encoded SHA-256 of the contents. Missing for artifacts uploaded by other services.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// How the bincode serialized value is encoded, eg. `zstd`. Missing for uncompressed
+    /// artifacts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl ArtifactMetadata {
+    /// Metadata describing the given contents.
+    pub fn for_data(data: &[u8], encoding: Option<&str>) -> Self {
+        Self {
+            sha256: Some(hex::encode(Sha256::digest(data))),
+            encoding: encoding.map(str::to_string),
+        }
+    }
+
+    /// Encode as S3 user-defined object metadata.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        if let Some(sha256) = &self.sha256 {
+            map.insert(SHA256_KEY.to_string(), sha256.clone());
+        }
+        if let Some(encoding) = &self.encoding {
+            map.insert(ENCODING_KEY.to_string(), encoding.clone());
+        }
+        map
+    }
+
+    /// Decode from S3 user-defined object metadata.
+    pub fn from_map(map: &HashMap<String, String>) -> Self {
+        Self {
+            sha256: map.get(SHA256_KEY).cloned(),
+            encoding: map.get(ENCODING_KEY).cloned(),
+        }
+    }
+
+    /// Deserialize the contents of an artifact, decompressing them first if needed.
+    fn deserialize<T: DeserializeOwned>(&self, data: impl BufRead) -> Result<T> {
+        match self.encoding.as_deref() {
+            None => Ok(bincode::deserialize_from(data)?),
+            Some(ZSTD_ENCODING) => {
+                let decoder = zstd::Decoder::with_buffer(data)?;
+                Ok(bincode::deserialize_from(decoder)?)
+            }
+            Some(encoding) => Err(anyhow::anyhow!(
+                "Unsupported artifact encoding: {}",
+                encoding
+            )),
+        }
+    }
+
+    /// Check the contents of an artifact against the checksum it was uploaded with, if any.
+    fn verify(&self, id: &str, mut data: impl Read) -> Result<(), ArtifactError> {
+        let Some(expected) = &self.sha256 else {
+            return Ok(());
+        };
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut data, &mut hasher)?;
+        let actual = hex::encode(hasher.finalize());
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(ArtifactError::Corrupted {
+                id: id.to_string(),
+                expected: expected.clone(),
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+const SHA256_KEY: &str = "sha256";
+const ENCODING_KEY: &str = "encoding";
+
+/// Encoding of zstd compressed artifacts.
+const ZSTD_ENCODING: &str = "zstd";
+
+/// Errors from reading an artifact.
+#[derive(Debug, Error)]
+pub enum ArtifactError {
+    #[error("artifact '{id}' corrupted: expected SHA-256 {expected} but got {actual}")]
+    Corrupted {
+        id: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("failed to read artifact")]
+    Io(#[from] std::io::Error),
+}
+
+/// An artifact kept in a file, to be deserialized without downloading it again or holding its
+/// contents in memory.
+pub struct ArtifactFile {
+    file: File,
+    metadata: ArtifactMetadata,
+}
+
+impl ArtifactFile {
+    /// Use a local file of bincode serialized contents as an artifact.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            metadata: ArtifactMetadata::default(),
+        })
+    }
+
+    /// Deserialize the contents of the artifact.
+    pub async fn read<T: DeserializeOwned + Send + 'static>(&self) -> Result<T> {
+        let mut file = self.file.try_clone()?;
+        let metadata = self.metadata.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            file.rewind()?;
+            debug_span!("deserialize").in_scope(|| metadata.deserialize(BufReader::new(&file)))
+        })
+        .await?
+    }
+}
+
+/// A place artifacts are read from and written to, keyed by artifact ID.
+#[async_trait]
+pub trait ArtifactStore: Send + Sync {
+    /// Read the contents of an artifact and the metadata it was written with.
+    async fn get(&self, id: &str) -> Result<(Vec<u8>, ArtifactMetadata)>;
+
+    /// Write the contents of an artifact to a file instead of memory, for artifacts too large to
+    /// hold in memory next to their deserialized value.
+    async fn get_to_file(&self, id: &str, file: &File) -> Result<ArtifactMetadata>;
+
+    /// Write the contents of an artifact, replacing any previous contents.
+    async fn put(&self, id: &str, data: Vec<u8>, metadata: &ArtifactMetadata) -> Result<()>;
+}
+
+/// Artifacts stored as files in a local directory.
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    /// Create a store in the given directory, creating it if needed.
+    pub async fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create artifact directory {:?}", dir))?;
+
+        Ok(Self { dir })
+    }
+}
+
+/// Write a file through a temporary file so readers never see partial contents.
+async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tokio::fs::write(&tmp_path, data)
+        .await
+        .with_context(|| format!("Failed to write {:?}", tmp_path))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to write {:?}", path))?;
+
+    Ok(())
+}
+
+impl LocalStore {
+    /// Path of a file of an artifact. IDs must be plain file names so that artifacts can't be read
+    /// or written outside of the store's directory.
+    fn path(&self, id: &str, suffix: &str) -> Result<PathBuf> {
+        let mut components = Path::new(id).components();
+        let is_file_name = matches!(components.next(), Some(Component::Normal(_)))
+            && components.next().is_none()
+            && !id.contains(['/', '\\']);
+        if !is_file_name {
+            return Err(anyhow::anyhow!("Invalid artifact ID '{}'", id));
+        }
+
+        Ok(self.dir.join(format!("{}{}", id, suffix)))
+    }
+
+    /// Metadata is kept next to the artifact in `<id>.meta.json`.
+    async fn metadata(&self, id: &str) -> Result<ArtifactMetadata> {
+        match tokio::fs::read(self.path(id, ".meta.json")?).await {
+            Ok(metadata) => Ok(serde_json::from_slice(&metadata)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ArtifactMetadata::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for LocalStore {
+    async fn get(&self, id: &str) -> Result<(Vec<u8>, ArtifactMetadata)> {
+        let path = self.path(id, "")?;
+        let data = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read artifact {:?}", path))?;
+
+        Ok((data, self.metadata(id).await?))
+    }
+
+    async fn get_to_file(&self, id: &str, file: &File) -> Result<ArtifactMetadata> {
+        let path = self.path(id, "")?;
+        let mut src = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("Failed to read artifact {:?}", path))?;
+        let mut dst = tokio::fs::File::from_std(file.try_clone()?);
+        tokio::io::copy(&mut src, &mut dst).await?;
+        dst.flush().await?;
+
+        self.metadata(id).await
+    }
+
+    async fn put(&self, id: &str, data: Vec<u8>, metadata: &ArtifactMetadata) -> Result<()> {
+        // Write the metadata first so the artifact is never read without its checksum.
+        let metadata_path = self.path(id, ".meta.json")?;
+        write_atomic(&metadata_path, &serde_json::to_vec(metadata)?).await?;
+        write_atomic(&self.path(id, "")?, &data).await
+    }
+}
+
+/// Open the artifact store selected by `artifact_store`: `s3` or `local`, which keeps artifacts in
+/// `artifact_dir`.
+pub async fn init_artifact_store() -> Result<()> {
+    let store: Arc<dyn ArtifactStore> = match config().artifact_store {
+        ArtifactStoreKind::S3 => Arc::new(S3Store::from_config(config()).await?),
+        ArtifactStoreKind::Local => Arc::new(LocalStore::new(&config().artifact_dir).await?),
+    };
+    ARTIFACT_STORE
+        .set(store)
+        .map_err(|_| anyhow::anyhow!("Artifact store is already open"))
+}
+
+/// Get the artifact store opened by [`init_artifact_store`].
+pub fn artifact_store() -> Result<&'static Arc<dyn ArtifactStore>> {
+    ARTIFACT_STORE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("Artifact store is not open"))
+}
+
+/// Transfers of the artifacts of the `ClusterService` protocol through the artifact store.
+#[async_trait]
+pub trait ArtifactExt {
+    fn new(id: &str, label: &str) -> Self;
+
+    /// Download an artifact and deserialize it in memory.
+    async fn download<T: DeserializeOwned + Send>(
+        &self,
+        client: &HttpClientWithMiddleware,
+    ) -> Result<T>;
+
+    /// Download an artifact to a temporary file and check it against its checksum, so that it
+    /// can be deserialized from there as often as needed.
+    async fn download_to_file(&self, client: &HttpClientWithMiddleware) -> Result<ArtifactFile>;
+
+    /// Download an artifact through a temporary file and deserialize it from there, so that
+    /// large artifacts such as stdin with deferred proofs are never held in memory twice.
+    async fn download_streaming<T: DeserializeOwned + Send + 'static>(
+        &self,
+        client: &HttpClientWithMiddleware,
+    ) -> Result<T>;
+
+    /// Upload an artifact as plain bincode, as clients of the prover network expect of the
+    /// artifacts they read, such as the proof of a proof request.
+    async fn upload<T: Serialize + Send>(
+        &self,
+        client: &HttpClientWithMiddleware,
+        item: T,
+    ) -> Result<()>;
+
+    /// Upload an artifact only read by worker nodes, compressing it if `artifact_compression`
+    /// is enabled.
+    async fn upload_intermediate<T: Serialize + Send>(
+        &self,
+        client: &HttpClientWithMiddleware,
+        item: T,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+impl ArtifactExt for Artifact {
+    fn new(id: &str, label: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            label: label.to_string(),
+            expiry: None,
+        }
+    }
+
+    #[instrument(name = "download", level = "info", fields(label = self.label, id = self.id), skip_all)]
+    async fn download<T: DeserializeOwned + Send>(
+        &self,
+        _client: &HttpClientWithMiddleware,
+    ) -> Result<T> {
+        let (data, metadata) = artifact_store()?.get(&self.id).await?;
+        ARTIFACT_BYTES
+            .with_label_values(&["download"])
+            .inc_by(data.len() as u64);
+        debug_span!("verify").in_scope(|| metadata.verify(&self.id, data.as_slice()))?;
+        let deserialized =
+            debug_span!("deserialize").in_scope(|| metadata.deserialize(data.as_slice()))?;
+        Ok(deserialized)
+    }
+
+    #[instrument(name = "download_to_file", level = "info", fields(label = self.label, id = self.id), skip_all)]
+    async fn download_to_file(&self, _client: &HttpClientWithMiddleware) -> Result<ArtifactFile> {
+        let mut file = tempfile::tempfile()?;
+        let metadata = artifact_store()?.get_to_file(&self.id, &file).await?;
+        ARTIFACT_BYTES
+            .with_label_values(&["download"])
+            .inc_by(file.metadata()?.len());
+
+        let id = self.id.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            file.rewind()?;
+            debug_span!("verify").in_scope(|| metadata.verify(&id, BufReader::new(&file)))?;
+            Ok::<_, anyhow::Error>(ArtifactFile { file, metadata })
+        })
+        .await?
+    }
+
+    async fn download_streaming<T: DeserializeOwned + Send + 'static>(
+        &self,
+        client: &HttpClientWithMiddleware,
+    ) -> Result<T> {
+        self.download_to_file(client).await?.read().await
+    }
+
+    async fn upload<T: Serialize + Send>(
+        &self,
+        _client: &HttpClientWithMiddleware,
+        item: T,
+    ) -> Result<()> {
+        upload_with(self, item, None).await
+    }
+
+    async fn upload_intermediate<T: Serialize + Send>(
+        &self,
+        _client: &HttpClientWithMiddleware,
+        item: T,
+    ) -> Result<()> {
+        upload_with(self, item, config().artifact_compression_level).await
+    }
+}
+
+#[instrument(name = "upload", level = "info", fields(label = artifact.label, id = artifact.id), skip_all)]
+async fn upload_with<T: Serialize + Send>(
+    artifact: &Artifact,
+    item: T,
+    compression_level: Option<i32>,
+) -> Result<()> {
+    let mut data = debug_span!("serialize").in_scope(|| bincode::serialize(&item))?;
+    let mut encoding = None;
+    if let Some(level) = compression_level {
+        data = debug_span!("compress").in_scope(|| zstd::encode_all(data.as_slice(), level))?;
+        encoding = Some(ZSTD_ENCODING);
+    }
+    let metadata = debug_span!("checksum").in_scope(|| ArtifactMetadata::for_data(&data, encoding));
+    ARTIFACT_BYTES
+        .with_label_values(&["upload"])
+        .inc_by(data.len() as u64);
+    artifact_store()?.put(&artifact.id, data, &metadata).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn put(store: &LocalStore, id: &str, value: &[u64], encoding: Option<&str>) {
+        let mut data = bincode::serialize(value).unwrap();
+        if encoding == Some(ZSTD_ENCODING) {
+            data = zstd::encode_all(data.as_slice(), 3).unwrap();
+        }
+        let metadata = ArtifactMetadata::for_data(&data, encoding);
+        store.put(id, data, &metadata).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).await.unwrap();
+        let value = vec![1u64, 2, 3];
+
+        put(&store, "artifact", &value, None).await;
+        let (data, metadata) = store.get("artifact").await.unwrap();
+        assert_eq!(metadata.encoding, None);
+        let read: Vec<u64> = metadata.deserialize(data.as_slice()).unwrap();
+        assert_eq!(read, value);
+    }
+
+    #[tokio::test]
+    async fn corrupted_artifacts_fail_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).await.unwrap();
+        let metadata = ArtifactMetadata::for_data(b"expected", None);
+        store
+            .put("artifact", b"actual".to_vec(), &metadata)
+            .await
+            .unwrap();
+
+        let (data, metadata) = store.get("artifact").await.unwrap();
+        let error = metadata.verify("artifact", data.as_slice()).unwrap_err();
+        assert!(matches!(error, ArtifactError::Corrupted { id, .. } if id == "artifact"));
+    }
+
+    #[tokio::test]
+    async fn artifacts_without_metadata_are_plain() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).await.unwrap();
+        let value = vec![7u64];
+        std::fs::write(
+            dir.path().join("artifact"),
+            bincode::serialize(&value).unwrap(),
+        )
+        .unwrap();
+
+        let (data, metadata) = store.get("artifact").await.unwrap();
+        assert_eq!(metadata, ArtifactMetadata::default());
+        metadata.verify("artifact", data.as_slice()).unwrap();
+        let read: Vec<u64> = metadata.deserialize(data.as_slice()).unwrap();
+        assert_eq!(read, value);
+    }
+
+    #[tokio::test]
+    async fn ids_outside_the_store_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path().join("store")).await.unwrap();
+        let escaped = dir.path().join("escaped");
+        let metadata = ArtifactMetadata::default();
+
+        for id in [
+            "",
+            ".",
+            "..",
+            "../escaped",
+            "nested/artifact",
+            "nested\\artifact",
+            escaped.to_str().unwrap(),
+        ] {
+            assert!(store.put(id, Vec::new(), &metadata).await.is_err());
+            assert!(store.get(id).await.is_err());
+        }
+        assert!(!escaped.exists());
+    }
+
+    #[test]
+    fn metadata_map_round_trip() {
+        let metadata = ArtifactMetadata::for_data(b"data", Some(ZSTD_ENCODING));
+        assert_eq!(ArtifactMetadata::from_map(&metadata.to_map()), metadata);
+        assert!(ArtifactMetadata::default().to_map().is_empty());
+    }
+
+    #[tokio::test]
+    async fn compressed_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).await.unwrap();
+        let value = vec![1u64, 2, 3];
+
+        put(&store, "artifact", &value, Some(ZSTD_ENCODING)).await;
+        let (data, metadata) = store.get("artifact").await.unwrap();
+        assert_eq!(metadata.encoding.as_deref(), Some(ZSTD_ENCODING));
+        metadata.verify("artifact", data.as_slice()).unwrap();
+        let read: Vec<u64> = metadata.deserialize(data.as_slice()).unwrap();
+        assert_eq!(read, value);
+    }
+
+    #[test]
+    fn unknown_encodings_are_rejected() {
+        let metadata = ArtifactMetadata {
+            sha256: None,
+            encoding: Some("gzip".to_string()),
+        };
+        assert!(metadata.deserialize::<Vec<u64>>(&b""[..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn artifact_file_reads_repeatedly() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).await.unwrap();
+        let value = vec![4u64, 5, 6];
+        put(&store, "artifact", &value, Some(ZSTD_ENCODING)).await;
+
+        let file = tempfile::tempfile().unwrap();
+        let metadata = store.get_to_file("artifact", &file).await.unwrap();
+        let artifact = ArtifactFile { file, metadata };
+        assert_eq!(artifact.read::<Vec<u64>>().await.unwrap(), value);
+        assert_eq!(artifact.read::<Vec<u64>>().await.unwrap(), value);
+    }
+}
@@ -0,0 +1,613 @@
+//! Task worker for the cluster's split proving pipeline.
+//!
+//! A proof is broken into the stages of the `TaskType` pipeline so that its stages can run on
+//! different machines, each stage on a single one. The controller task for a proof creates one
+//! child task per stage, waits for it, and assembles the final proof once the last stage is done:
+//!
+//! - `Sp1CommitShard`: prove all core shards of the program, in a single task.
+//! - `Sp1RecursionReduceBatch`: reduce the core proof and deferred proofs to a compressed proof.
+//! - `Sp1ControllerShrink`: shrink the compressed proof.
+//! - `Sp1ControllerWrap`: wrap the shrunken proof into the BN254 field.
+//! - `Sp1ControllerPlonk`: generate the PlonK or Groth16 proof from the wrapped proof.
+//!
+//! Intermediate proofs are passed between stages as artifacts.
+//!
+//! A single proof is not split across machines: the core proof is one `Sp1CommitShard` task, as
+//! the prover only proves the shards of a program in one `prove_core` call over the whole
+//! execution. Proving shards as separate tasks and reducing them in a recursion tree needs a
+//! prover API for proving a range of shards on its own.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+use prover_common::artifact::{
+    Artifact, BumpTasksRequest, ClusterServiceClient, CreateArtifactRequest, CreateTaskRequest,
+    FetchTaskStatusRequest, PopTasksRequest, Task, TaskRequestData, TaskStatus, TaskType,
+    UpdateTaskStatusRequest,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use serde::{Deserialize, Serialize};
+use sp1_core_executor::SP1Context;
+use sp1_prover::{InnerSC, OuterSC, SP1CoreProof, SP1Prover, SP1ReduceProof};
+use sp1_sdk::{
+    proto::network::ProofMode, ProverClient, SP1Proof, SP1ProofWithPublicValues, SP1PublicValues,
+    SP1Stdin, SP1VerifyingKey,
+};
+use sp1_stark::SP1ProverOpts;
+use tracing::{Instrument, Span};
+use twirp::client::Client as TwirpClient;
+
+use crate::cache::{program_cache, CachedProgram};
+use crate::config::config;
+use crate::metrics::TASKS;
+use crate::prove::{preflight, verify_proof, CycleLimitExceeded};
+use crate::statics::{CIRCUITS, DRAINING, HTTP_CLIENT_WITH_MIDDLEWARE};
+use crate::store::ArtifactExt;
+use crate::telemetry;
+
+/// How often the worker asks the cluster for new tasks.
+const POP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a running task is bumped so the cluster does not consider it dropped.
+const BUMP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a controller checks on the child task it is waiting for.
+const CHILD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a controller waits for a child task, eg. one no worker picks up, before the attempt
+/// is failed and retried.
+const CHILD_TASK_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long intermediate artifacts are kept.
+const ARTIFACT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Weight of controller tasks. They mostly wait on their children, so they don't take from the
+/// proving budget, or controllers waiting for room could keep their own children from running.
+/// Their number is bounded by the master's `CLUSTER_MAX_PROOFS`.
+pub const CONTROLLER_TASK_WEIGHT: u32 = 0;
+
+/// Weight of the tasks that do the actual proving.
+pub const PROVE_TASK_WEIGHT: u32 = 4;
+
+/// Metadata attached to every task of a proof.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskMetadata {
+    pub mode: ProofMode,
+    /// W3C `traceparent` of the span that created the task, see [`telemetry::task_span`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+}
+
+/// Worker that pops tasks from the cluster service and executes them.
+pub struct TaskWorker {
+    cluster: TwirpClient,
+    prover: Arc<SP1Prover>,
+    /// Used to verify finished proofs before they are uploaded.
+    client: Arc<ProverClient>,
+    http_client: ClientWithMiddleware,
+    in_flight_weight: AtomicU32,
+    in_flight_tasks: AtomicU32,
+}
+
+/// Whether this worker knows how to run a task type.
+fn is_supported(task_type: TaskType) -> bool {
+    matches!(
+        task_type,
+        TaskType::Sp1ControllerExecute
+            | TaskType::Sp1CommitShard
+            | TaskType::Sp1RecursionReduceBatch
+            | TaskType::Sp1ControllerShrink
+            | TaskType::Sp1ControllerWrap
+            | TaskType::Sp1ControllerPlonk
+    )
+}
+
+/// The weight of a task, as scheduled by the controller that created it.
+fn task_weight(task_type: TaskType) -> u32 {
+    match task_type {
+        TaskType::Sp1ControllerExecute => CONTROLLER_TASK_WEIGHT,
+        _ => PROVE_TASK_WEIGHT,
+    }
+}
+
+fn type_of(task: &Task) -> TaskType {
+    task.data
+        .as_ref()
+        .and_then(|data| TaskType::try_from(data.task_type).ok())
+        .unwrap_or(TaskType::UnspecifiedTaskType)
+}
+
+fn input(task: &Task, index: usize) -> Result<&Artifact> {
+    task.data
+        .as_ref()
+        .and_then(|data| data.inputs.get(index))
+        .ok_or_else(|| anyhow::anyhow!("Task '{}' is missing input {}", task.id, index))
+}
+
+fn output(task: &Task, index: usize) -> Result<&Artifact> {
+    task.data
+        .as_ref()
+        .and_then(|data| data.outputs.get(index))
+        .ok_or_else(|| anyhow::anyhow!("Task '{}' is missing output {}", task.id, index))
+}
+
+fn metadata(task: &Task) -> Result<TaskMetadata> {
+    let data = task
+        .data
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Task '{}' has no data", task.id))?;
+    Ok(serde_json::from_str(&data.metadata)?)
+}
+
+impl TaskWorker {
+    /// Create a task worker for the cluster service at the given base URL, eg.
+    /// `http://master-node:3000/twirp/`.
+    pub fn new(
+        cluster_rpc: &str,
+        prover: Arc<SP1Prover>,
+        client: Arc<ProverClient>,
+    ) -> Result<Self> {
+        let cluster = TwirpClient::from_base_url(url::Url::parse(cluster_rpc)?)?;
+
+        Ok(Self {
+            cluster,
+            prover,
+            client,
+            http_client: HTTP_CLIENT_WITH_MIDDLEWARE.lock().unwrap().clone(),
+            in_flight_weight: AtomicU32::new(0),
+            in_flight_tasks: AtomicU32::new(0),
+        })
+    }
+
+    /// Number of tasks running on this worker, controllers included.
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight_tasks.load(Ordering::SeqCst)
+    }
+
+    /// Pop and execute tasks until the worker starts shutting down.
+    pub async fn run(self: Arc<Self>) {
+        let budget = config().task_weight_budget;
+        info!(
+            "Task worker started as {} with a weight budget of {}.",
+            config().worker_type.as_str_name(),
+            budget
+        );
+        if budget < PROVE_TASK_WEIGHT {
+            warn!(
+                "TASK_WEIGHT_BUDGET is below the weight of proving tasks ({}), only controller tasks will be run.",
+                PROVE_TASK_WEIGHT
+            );
+        }
+
+        while !DRAINING.load(Ordering::SeqCst) {
+            // Controllers weigh nothing, so they are popped even when the budget is used up.
+            let available = budget.saturating_sub(self.in_flight_weight.load(Ordering::SeqCst));
+            match self
+                .cluster
+                .pop_tasks(PopTasksRequest {
+                    worker_type: config().worker_type as i32,
+                    max_weight: available,
+                })
+                .await
+            {
+                Ok(res) => {
+                    for task in res.tasks {
+                        self.in_flight_weight
+                            .fetch_add(task_weight(type_of(&task)), Ordering::SeqCst);
+                        self.in_flight_tasks.fetch_add(1, Ordering::SeqCst);
+                        let worker = self.clone();
+                        tokio::spawn(async move { worker.execute(task).await });
+                    }
+                }
+                Err(e) => warn!("Failed to pop tasks: {:?}", e),
+            }
+
+            tokio::time::sleep(POP_INTERVAL).await;
+        }
+
+        info!("Task worker stopped taking on tasks.");
+    }
+
+    /// Execute a task, keeping it alive on the cluster while it runs and reporting its outcome.
+    async fn execute(self: Arc<Self>, task: Task) {
+        let task_type = type_of(&task);
+        info!(
+            "Running task '{}' ({}) for proof with ID '{}'.",
+            task.id,
+            task_type.as_str_name(),
+            task.proof_id
+        );
+
+        // Bump the task periodically while it runs.
+        let cluster = self.cluster.clone();
+        let task_id = task.id.clone();
+        let bumper = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(BUMP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = cluster
+                    .bump_tasks(BumpTasksRequest {
+                        task_ids: vec![task_id.clone()],
+                    })
+                    .await
+                {
+                    warn!("Failed to bump task '{}': {:?}", task_id, e);
+                }
+            }
+        });
+
+        // Unsupported tasks fail for good, anything else may succeed on another attempt.
+        let traceparent = metadata(&task).ok().and_then(|m| m.traceparent);
+        let span = telemetry::task_span(
+            &task.id,
+            task_type.as_str_name(),
+            &task.proof_id,
+            traceparent.as_deref(),
+        );
+        let status = match self.handle(task_type, &task).instrument(span).await {
+            Ok(()) => TaskStatus::Succeeded,
+            Err(e) if !is_supported(task_type) || e.is::<CycleLimitExceeded>() => {
+                error!("Task '{}' cannot be run: {:?}", task.id, e);
+                TaskStatus::FailedFatal
+            }
+            Err(e) => {
+                error!("Task '{}' failed: {:?}", task.id, e);
+                TaskStatus::FailedRetryable
+            }
+        };
+        TASKS
+            .with_label_values(&[task_type.as_str_name(), status.as_str_name()])
+            .inc();
+        bumper.abort();
+
+        if let Err(e) = self
+            .cluster
+            .update_task_status(UpdateTaskStatusRequest {
+                id: task.id.clone(),
+                status: status as i32,
+            })
+            .await
+        {
+            error!("Failed to update status of task '{}': {:?}", task.id, e);
+        }
+        self.in_flight_weight
+            .fetch_sub(task_weight(task_type), Ordering::SeqCst);
+        self.in_flight_tasks.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    async fn handle(&self, task_type: TaskType, task: &Task) -> Result<()> {
+        match task_type {
+            TaskType::Sp1ControllerExecute => self.control(task).await,
+            TaskType::Sp1CommitShard => self.prove_core(task).await,
+            TaskType::Sp1RecursionReduceBatch => self.compress(task).await,
+            TaskType::Sp1ControllerShrink => self.shrink(task).await,
+            TaskType::Sp1ControllerWrap => self.wrap(task).await,
+            TaskType::Sp1ControllerPlonk => self.wrap_plonk(task).await,
+            _ => Err(anyhow::anyhow!(
+                "Unsupported task type {}",
+                task_type.as_str_name()
+            )),
+        }
+    }
+
+    /// Create an artifact for an intermediate proof.
+    async fn create_artifact(&self, label: &str) -> Result<Artifact> {
+        let expiry = SystemTime::now().duration_since(UNIX_EPOCH)? + ARTIFACT_TTL;
+        self.cluster
+            .create_artifact(CreateArtifactRequest {
+                label: label.to_string(),
+                expiry: expiry.as_secs() as u32,
+            })
+            .await?
+            .artifact
+            .ok_or_else(|| anyhow::anyhow!("Cluster did not return an artifact"))
+    }
+
+    /// Create a child task of a controller and wait for it to finish, for at most
+    /// `CHILD_TASK_TIMEOUT`.
+    async fn run_child(
+        &self,
+        parent: &Task,
+        task_type: TaskType,
+        inputs: &[&Artifact],
+        outputs: &[&Artifact],
+    ) -> Result<()> {
+        // Children continue the trace from the parent's task span.
+        let mut metadata = metadata(parent)?;
+        if let Some(traceparent) = telemetry::traceparent(&Span::current()) {
+            metadata.traceparent = Some(traceparent);
+        }
+        let metadata = serde_json::to_string(&metadata)?;
+        let id = self
+            .cluster
+            .create_task(CreateTaskRequest {
+                parent_id: Some(parent.id.clone()),
+                proof_id: parent.proof_id.clone(),
+                data: Some(TaskRequestData {
+                    task_type: task_type as i32,
+                    input_artifact_ids: inputs.iter().map(|a| a.id.clone()).collect(),
+                    output_artifact_ids: outputs.iter().map(|a| a.id.clone()).collect(),
+                    metadata,
+                    weight: task_weight(task_type),
+                }),
+            })
+            .await?
+            .id;
+
+        let deadline = Instant::now() + CHILD_TASK_TIMEOUT;
+        loop {
+            let status = self
+                .cluster
+                .fetch_task_status(FetchTaskStatusRequest { id: id.clone() })
+                .await?
+                .status;
+            match TaskStatus::try_from(status) {
+                Ok(TaskStatus::Succeeded) => return Ok(()),
+                Ok(TaskStatus::FailedFatal) => {
+                    return Err(anyhow::anyhow!(
+                        "Task '{}' ({}) failed",
+                        id,
+                        task_type.as_str_name()
+                    ))
+                }
+                _ if Instant::now() >= deadline => {
+                    return Err(anyhow::anyhow!(
+                        "Task '{}' ({}) did not finish in {:?}",
+                        id,
+                        task_type.as_str_name(),
+                        CHILD_TASK_TIMEOUT
+                    ))
+                }
+                _ => tokio::time::sleep(CHILD_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Controller for a whole proof. Inputs: program, stdin. Outputs: proof.
+    async fn control(&self, task: &Task) -> Result<()> {
+        let mode = metadata(task)?.mode;
+        let program = input(task, 0)?;
+        let stdin = input(task, 1)?;
+        let proof = output(task, 0)?;
+
+        // Execute the program before creating any proving tasks for it.
+        let cached = self.program(program).await?;
+        let stdin_file = stdin.download_to_file(&self.http_client).await?;
+        let vk = cached.vk.clone();
+        let prover = self.prover.clone();
+        let execute_stdin = stdin_file.read::<SP1Stdin>().await?;
+        preflight(&task.proof_id, move || {
+            let mut context = SP1Context::builder();
+            if let Some(max_cycles) = config().max_cycles {
+                context.max_cycles(max_cycles);
+            }
+            Ok(prover.execute(&cached.pk.elf, &execute_stdin, context.build())?)
+        })
+        .await?;
+
+        // Every mode starts from the core proof.
+        let core_proof = self.create_artifact("core_proof").await?;
+        let public_values = self.create_artifact("public_values").await?;
+        self.run_child(
+            task,
+            TaskType::Sp1CommitShard,
+            &[program, stdin],
+            &[&core_proof, &public_values],
+        )
+        .await?;
+
+        let public_values = public_values
+            .download::<SP1PublicValues>(&self.http_client)
+            .await?;
+        if mode == ProofMode::Core {
+            let core_proof = core_proof
+                .download::<SP1CoreProof>(&self.http_client)
+                .await?;
+            let result = SP1ProofWithPublicValues {
+                proof: SP1Proof::Core(core_proof.proof.0),
+                stdin: stdin_file.read().await?,
+                public_values,
+                sp1_version: sp1_prover::SP1_CIRCUIT_VERSION.to_string(),
+            };
+            return self.upload_proof(proof, result, vk).await;
+        }
+
+        let compressed = self.create_artifact("compressed_proof").await?;
+        self.run_child(
+            task,
+            TaskType::Sp1RecursionReduceBatch,
+            &[program, stdin, &core_proof],
+            &[&compressed],
+        )
+        .await?;
+
+        if mode == ProofMode::Compressed {
+            let compressed = compressed
+                .download::<SP1ReduceProof<InnerSC>>(&self.http_client)
+                .await?;
+            let result = SP1ProofWithPublicValues {
+                proof: SP1Proof::Compressed(compressed.proof),
+                stdin: stdin_file.read().await?,
+                public_values,
+                sp1_version: sp1_prover::SP1_CIRCUIT_VERSION.to_string(),
+            };
+            return self.upload_proof(proof, result, vk).await;
+        }
+
+        let shrunk = self.create_artifact("shrunk_proof").await?;
+        self.run_child(
+            task,
+            TaskType::Sp1ControllerShrink,
+            &[&compressed],
+            &[&shrunk],
+        )
+        .await?;
+
+        let wrapped = self.create_artifact("wrapped_proof").await?;
+        self.run_child(task, TaskType::Sp1ControllerWrap, &[&shrunk], &[&wrapped])
+            .await?;
+
+        let wrapped_proof = self.create_artifact("bn254_proof").await?;
+        self.run_child(
+            task,
+            TaskType::Sp1ControllerPlonk,
+            &[&wrapped],
+            &[&wrapped_proof],
+        )
+        .await?;
+
+        let wrapped_proof = wrapped_proof
+            .download::<SP1Proof>(&self.http_client)
+            .await?;
+        let result = SP1ProofWithPublicValues {
+            proof: wrapped_proof,
+            stdin: stdin_file.read().await?,
+            public_values,
+            sp1_version: sp1_prover::SP1_CIRCUIT_VERSION.to_string(),
+        };
+        self.upload_proof(proof, result, vk).await
+    }
+
+    /// Verify a finished proof against the verifying key of its program and upload it. Invalid
+    /// proofs fail the controller task, so it is retried rather than fulfilled.
+    async fn upload_proof(
+        &self,
+        artifact: &Artifact,
+        proof: SP1ProofWithPublicValues,
+        vk: SP1VerifyingKey,
+    ) -> Result<()> {
+        let proof = verify_proof(self.client.clone(), proof, vk).await?;
+        artifact.upload(&self.http_client, &proof).await
+    }
+
+    /// Get a program and its keys from the program cache, setting it up if needed.
+    async fn program(&self, artifact: &Artifact) -> Result<CachedProgram> {
+        let prover = self.prover.clone();
+        program_cache()?
+            .get(artifact, &self.http_client, move |elf| prover.setup(elf))
+            .await
+    }
+
+    /// Inputs: program, stdin. Outputs: core proof, public values. The whole program is proven
+    /// here, the shards are not split across tasks.
+    async fn prove_core(&self, task: &Task) -> Result<()> {
+        let program = self.program(input(task, 0)?).await?;
+        let stdin = input(task, 1)?
+            .download_streaming::<SP1Stdin>(&self.http_client)
+            .await?;
+
+        let prover = self.prover.clone();
+        let core_proof = tokio::task::spawn_blocking(move || {
+            prover.prove_core(
+                &program.pk,
+                &stdin,
+                SP1ProverOpts::default(),
+                SP1Context::default(),
+            )
+        })
+        .await??;
+
+        output(task, 1)?
+            .upload_intermediate(&self.http_client, &core_proof.public_values)
+            .await?;
+        output(task, 0)?
+            .upload_intermediate(&self.http_client, &core_proof)
+            .await
+    }
+
+    /// Inputs: program, stdin, core proof. Outputs: compressed proof.
+    async fn compress(&self, task: &Task) -> Result<()> {
+        let program = self.program(input(task, 0)?).await?;
+        let stdin = input(task, 1)?
+            .download_streaming::<SP1Stdin>(&self.http_client)
+            .await?;
+        let core_proof = input(task, 2)?
+            .download::<SP1CoreProof>(&self.http_client)
+            .await?;
+
+        let prover = self.prover.clone();
+        let compressed = tokio::task::spawn_blocking(move || {
+            let deferred_proofs = stdin.proofs.iter().map(|p| p.0.clone()).collect();
+            prover.compress(
+                &program.vk,
+                core_proof,
+                deferred_proofs,
+                SP1ProverOpts::default(),
+            )
+        })
+        .await??;
+
+        output(task, 0)?
+            .upload_intermediate(&self.http_client, &compressed)
+            .await
+    }
+
+    /// Inputs: compressed proof. Outputs: shrunken proof.
+    async fn shrink(&self, task: &Task) -> Result<()> {
+        let compressed = input(task, 0)?
+            .download::<SP1ReduceProof<InnerSC>>(&self.http_client)
+            .await?;
+
+        let prover = self.prover.clone();
+        let shrunk = tokio::task::spawn_blocking(move || {
+            prover.shrink(compressed, SP1ProverOpts::default())
+        })
+        .await??;
+
+        output(task, 0)?
+            .upload_intermediate(&self.http_client, &shrunk)
+            .await
+    }
+
+    /// Inputs: shrunken proof. Outputs: wrapped proof.
+    async fn wrap(&self, task: &Task) -> Result<()> {
+        let shrunk = input(task, 0)?
+            .download::<SP1ReduceProof<InnerSC>>(&self.http_client)
+            .await?;
+
+        let prover = self.prover.clone();
+        let wrapped = tokio::task::spawn_blocking(move || {
+            prover.wrap_bn254(shrunk, SP1ProverOpts::default())
+        })
+        .await??;
+
+        output(task, 0)?
+            .upload_intermediate(&self.http_client, &wrapped)
+            .await
+    }
+
+    /// Inputs: wrapped proof. Outputs: PlonK or Groth16 proof, depending on the proof mode.
+    async fn wrap_plonk(&self, task: &Task) -> Result<()> {
+        let mode = metadata(task)?.mode;
+        let wrapped = input(task, 0)?
+            .download::<SP1ReduceProof<OuterSC>>(&self.http_client)
+            .await?;
+        CIRCUITS.wait_ready().await;
+
+        let prover = self.prover.clone();
+        let proof = tokio::task::spawn_blocking(move || {
+            let artifacts_dir = sp1_sdk::install::try_install_circuit_artifacts();
+            match mode {
+                ProofMode::Plonk => Ok(SP1Proof::Plonk(
+                    prover.wrap_plonk_bn254(wrapped, &artifacts_dir),
+                )),
+                ProofMode::Groth16 => Ok(SP1Proof::Groth16(
+                    prover.wrap_groth16_bn254(wrapped, &artifacts_dir),
+                )),
+                _ => Err(anyhow::anyhow!("Proof mode {:?} is not wrapped", mode)),
+            }
+        })
+        .await??;
+
+        output(task, 0)?
+            .upload_intermediate(&self.http_client, &proof)
+            .await
+    }
+}
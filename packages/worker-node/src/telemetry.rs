@@ -0,0 +1,121 @@
+//! The trace context received from the master node and passed along cluster tasks. Logging and
+//! trace export are set up by the shared [`prover_common::telemetry`].
+
+use std::collections::HashMap;
+
+use opentelemetry::{propagation::TextMapPropagator, trace::TraceContextExt};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+pub use prover_common::telemetry::{init, shutdown};
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Parse a W3C `traceparent` header into its trace ID and parent span ID.
+fn parse_traceparent(traceparent: &str) -> Option<(&str, &str)> {
+    let mut parts = traceparent.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |s: &str, len: usize| s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit());
+    let valid =
+        is_hex(version, 2) && is_hex(trace_id, 32) && is_hex(parent_id, 16) && is_hex(flags, 2);
+
+    valid.then_some((trace_id, parent_id))
+}
+
+/// The root span of a proof job, continuing the master node's trace when the request carried a
+/// valid `traceparent` header and starting a new trace otherwise. When spans are exported, the
+/// span is exported as a child of the master node's proof span.
+pub fn proof_span(proof_id: &str, job_id: &str, traceparent: Option<&str>) -> Span {
+    let span = info_span!(
+        "proof",
+        proof_id = proof_id,
+        job_id = job_id,
+        trace_id = field::Empty,
+        parent_span_id = field::Empty
+    );
+    continue_trace(&span, traceparent);
+
+    span
+}
+
+/// The span of a cluster task, continuing the trace carried in the task's metadata the same way
+/// [`proof_span`] does.
+pub fn task_span(
+    task_id: &str,
+    task_type: &str,
+    proof_id: &str,
+    traceparent: Option<&str>,
+) -> Span {
+    let span = info_span!(
+        "task",
+        task_id = task_id,
+        task_type = task_type,
+        proof_id = proof_id,
+        trace_id = field::Empty,
+        parent_span_id = field::Empty
+    );
+    continue_trace(&span, traceparent);
+
+    span
+}
+
+/// The `traceparent` header continuing the trace of a span in another process, or `None` when
+/// spans are not exported.
+pub fn traceparent(span: &Span) -> Option<String> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+    carrier.remove("traceparent")
+}
+
+/// Make a span a child of the span a `traceparent` header points to, and record the IDs of the
+/// trace and of the parent span on it.
+fn continue_trace(span: &Span, traceparent: Option<&str>) {
+    let parent = traceparent.and_then(parse_traceparent);
+    if let (Some(traceparent), Some((_, parent_id))) = (traceparent, parent) {
+        let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+        span.set_parent(TraceContextPropagator::new().extract(&carrier));
+        span.record("parent_span_id", parent_id);
+    }
+
+    let span_context = span.context().span().span_context().clone();
+    let trace_id = if span_context.is_valid() {
+        span_context.trace_id().to_string()
+    } else {
+        parent
+            .map(|(trace_id, _)| trace_id.to_string())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+    };
+    span.record("trace_id", trace_id.as_str());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn parses_valid_traceparent() {
+        let traceparent = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        assert_eq!(parse_traceparent(&traceparent), Some((TRACE_ID, PARENT_ID)));
+        let traceparent = format!(" 00-{}-{}-00\n", TRACE_ID, PARENT_ID);
+        assert_eq!(parse_traceparent(&traceparent), Some((TRACE_ID, PARENT_ID)));
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        let invalid = [
+            String::new(),
+            "00".to_string(),
+            format!("00-{}-{}", TRACE_ID, PARENT_ID),
+            format!("0-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, &PARENT_ID[1..]),
+            format!("00-{}-{}-0x", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID.replace('4', "g"), PARENT_ID),
+        ];
+        for traceparent in &invalid {
+            assert_eq!(parse_traceparent(traceparent), None, "{}", traceparent);
+        }
+    }
+}